@@ -0,0 +1,229 @@
+use std::{
+    future::Future,
+    rc::Rc,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    task::{self, Poll},
+    time::Duration,
+};
+
+use futures::{
+    channel::oneshot,
+    future::LocalBoxFuture,
+    stream::{FuturesUnordered, StreamExt},
+    task::{waker, ArcWake},
+    FutureExt,
+};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    node::{decode_reply, Payload, RawMessage},
+    Clock, Error, Init, Message, Node, RpcError, Sender, Timer, TimerId,
+};
+
+/// A node whose handlers can `await` replies and timers. Every message and event gets its own
+/// task, so several handlers may be in flight at once: state shared between them lives behind
+/// `&self`, usually in a `RefCell`, and must not be borrowed across an `.await`.
+pub trait AsyncNode: 'static {
+    type Payload: 'static;
+    type Event: 'static;
+
+    fn init(&mut self, init: Init, ctx: Context<Self::Payload, Self::Event>);
+    fn message(
+        self: Rc<Self>,
+        message: Message<Self::Payload>,
+        ctx: Context<Self::Payload, Self::Event>,
+    ) -> impl Future<Output = Result<(), Error>>;
+    fn event(
+        self: Rc<Self>,
+        event: Self::Event,
+        ctx: Context<Self::Payload, Self::Event>,
+    ) -> impl Future<Output = ()> {
+        async move {
+            drop(event);
+            drop(ctx);
+            panic!("Unhandled event");
+        }
+    }
+}
+
+/// Events seen by the `Node` wrapping an `AsyncNode`: either a user event, or a signal that
+/// some pending task was woken and should be polled.
+#[derive(Clone, Debug)]
+pub enum Wakeup<E> {
+    Poll,
+    Event(E),
+}
+
+pub struct Context<P, E> {
+    sender: Sender<P, Wakeup<E>>,
+}
+
+impl<P, E> Clone for Context<P, E> {
+    fn clone(&self) -> Self {
+        Self {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<P, E> Context<P, E> {
+    fn new(sender: Sender<P, Wakeup<E>>) -> Self {
+        Self { sender }
+    }
+
+    pub fn clock(&self) -> Rc<dyn Clock> {
+        self.sender.clock()
+    }
+
+    pub fn now(&self) -> Duration {
+        self.sender.now()
+    }
+
+    pub fn send(&self, dest: String, reply: Option<usize>, payload: P) {
+        self.sender.send(dest, reply, payload);
+    }
+
+    pub fn error(&self, dest: String, reply: Option<usize>, error: Error) {
+        self.sender.error(dest, reply, error);
+    }
+
+    pub fn call(
+        &self,
+        dest: String,
+        payload: P,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<Message<P>, RpcError>>
+    where
+        P: DeserializeOwned + 'static,
+    {
+        let reply = self.request(dest, Payload::Typed(payload), timeout);
+        async move { reply.await.and_then(decode_reply) }
+    }
+
+    pub(crate) fn request(
+        &self,
+        dest: String,
+        payload: Payload<P>,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<RawMessage, RpcError>> {
+        let (tx, rx) = oneshot::channel();
+
+        self.sender.request(dest, payload, timeout, move |reply| {
+            let _ = tx.send(reply);
+            Wakeup::Poll
+        });
+
+        async move { rx.await.unwrap_or(Err(RpcError::Timeout)) }
+    }
+
+    pub fn schedule(&self, timer: Timer<E>) -> TimerId {
+        self.sender.schedule(timer.map(Wakeup::Event))
+    }
+
+    pub fn cancel(&self, id: TimerId) {
+        self.sender.cancel(id);
+    }
+
+    pub fn sleep(&self, duration: Duration) -> impl Future<Output = ()> {
+        let (tx, rx) = oneshot::channel();
+
+        self.sender.after(duration, move || {
+            let _ = tx.send(());
+            Wakeup::Poll
+        });
+
+        async move {
+            let _ = rx.await;
+        }
+    }
+}
+
+/// Runs an `AsyncNode` as a regular `Node`, polling its in-flight handlers whenever
+/// something they wait on completes.
+pub struct Async<N> {
+    node: Rc<N>,
+    tasks: FuturesUnordered<LocalBoxFuture<'static, ()>>,
+}
+
+impl<N> Async<N>
+where
+    N: AsyncNode,
+{
+    pub fn new(node: N) -> Self {
+        Self {
+            node: Rc::new(node),
+            tasks: FuturesUnordered::new(),
+        }
+    }
+
+    // `FuturesUnordered` gives up with `Pending` after a round over its tasks, waking itself
+    // if any task is ready again, so polling goes on for as long as that happens
+    fn poll(&mut self) {
+        let woken = Arc::new(Woken(AtomicBool::new(true)));
+        let waker = waker(woken.clone());
+        let mut cx = task::Context::from_waker(&waker);
+
+        while woken.0.swap(false, Ordering::Relaxed) {
+            while let Poll::Ready(Some(())) = self.tasks.poll_next_unpin(&mut cx) {}
+        }
+    }
+}
+
+// whether the tasks were woken while being polled
+struct Woken(AtomicBool);
+
+impl ArcWake for Woken {
+    fn wake_by_ref(arc_self: &Arc<Self>) {
+        arc_self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+impl<N> Node for Async<N>
+where
+    N: AsyncNode,
+{
+    type Payload = N::Payload;
+    type Event = Wakeup<N::Event>;
+
+    fn init(&mut self, init: Init, sender: Sender<Self::Payload, Self::Event>) {
+        Rc::get_mut(&mut self.node)
+            .expect("Node initialized while handlers are running")
+            .init(init, Context::new(sender));
+    }
+
+    fn message(
+        &mut self,
+        message: Message<Self::Payload>,
+        sender: Sender<Self::Payload, Self::Event>,
+    ) -> Result<(), Error> {
+        let (src, id) = (message.src.clone(), message.body.id);
+
+        let ctx = Context::new(sender);
+        let task = self.node.clone().message(message, ctx.clone());
+
+        self.tasks.push(
+            async move {
+                if let Err(error) = task.await {
+                    ctx.error(src, id, error);
+                }
+            }
+            .boxed_local(),
+        );
+
+        self.poll();
+
+        Ok(())
+    }
+
+    fn event(&mut self, event: Self::Event, sender: Sender<Self::Payload, Self::Event>) {
+        if let Wakeup::Event(event) = event {
+            let task = self.node.clone().event(event, Context::new(sender));
+            self.tasks.push(task.boxed_local());
+        }
+
+        self.poll();
+    }
+}
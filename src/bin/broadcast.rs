@@ -49,14 +49,18 @@ impl Node for BroadcastNode {
     type Payload = BroadcastPayload;
     type Event = ();
 
-    fn init(&mut self, init: Init) {
+    fn init(&mut self, init: Init, _: Sender<BroadcastPayload>) {
         self.id = init.id;
         self.seen =
             HashMap::from_iter(init.nodes.iter().map(|node| (node.clone(), HashSet::new())));
         self.neigs = init.nodes;
     }
 
-    fn message(&mut self, message: Message<BroadcastPayload>, sender: Sender<BroadcastPayload>) {
+    fn message(
+        &mut self,
+        message: Message<BroadcastPayload>,
+        sender: Sender<BroadcastPayload>,
+    ) -> Result<(), Error> {
         let dst = message.src;
         let rply = message.body.id;
 
@@ -82,8 +86,10 @@ impl Node for BroadcastNode {
                 self.set.extend(messages);
             }
 
-            _ => unreachable!(),
+            _ => return Err(ErrorCode::NotSupported.into()),
         };
+
+        Ok(())
     }
 
     fn event(&mut self, _: (), sender: Sender<BroadcastPayload>) {
@@ -108,3 +114,70 @@ fn main() {
         .run(BroadcastNode::new())
         .unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    use crabstorm::sim::{Nemesis, Sim};
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn broadcast_reaches_every_node() {
+        let mut sim = Sim::new(5, 42, BroadcastNode::new).event(Duration::from_millis(200), ());
+
+        for (message, node) in sim.node_ids().iter().enumerate() {
+            let payload = BroadcastPayload::Broadcast { message };
+            let reply = sim.call(node, payload, TIMEOUT).unwrap();
+            assert!(matches!(reply.body.payload, BroadcastPayload::BroadcastOk));
+        }
+
+        sim.run_for(Duration::from_secs(1));
+
+        for node in sim.node_ids() {
+            let reply = sim.call(&node, BroadcastPayload::Read, TIMEOUT).unwrap();
+            let BroadcastPayload::ReadOk { messages } = reply.body.payload else {
+                panic!("unexpected reply {:?}", reply);
+            };
+
+            assert_eq!(
+                messages.into_iter().collect::<HashSet<_>>(),
+                (0..5).collect()
+            );
+        }
+    }
+
+    #[test]
+    fn broadcast_survives_partitions() {
+        let mut sim = Sim::new(5, 42, BroadcastNode::new)
+            .event(Duration::from_millis(200), ())
+            .drop_rate(0.1)
+            .nemesis(Nemesis::Partition, Duration::from_millis(500));
+
+        for (message, node) in sim.node_ids().iter().enumerate() {
+            let payload = BroadcastPayload::Broadcast { message };
+            let reply = sim.call(node, payload, TIMEOUT).unwrap();
+            assert!(matches!(reply.body.payload, BroadcastPayload::BroadcastOk));
+        }
+
+        sim.run_for(Duration::from_secs(3));
+        sim.stop_nemesis();
+        sim.run_for(Duration::from_secs(2));
+
+        for node in sim.node_ids() {
+            let reply = sim.call(&node, BroadcastPayload::Read, TIMEOUT).unwrap();
+            let BroadcastPayload::ReadOk { messages } = reply.body.payload else {
+                panic!("unexpected reply {:?}", reply);
+            };
+
+            assert_eq!(
+                messages.into_iter().collect::<HashSet<_>>(),
+                (0..5).collect()
+            );
+        }
+    }
+}
@@ -18,6 +18,14 @@ enum CounterPayload {
     GossipOk { value: usize },
 }
 
+#[derive(Clone, Debug)]
+enum CounterEvent {
+    Gossip,
+    Confirmed { node: String, value: usize },
+}
+
+const GOSSIP_TIMEOUT: Duration = Duration::from_millis(500);
+
 struct CounterNode {
     id: String,
     acc: usize,
@@ -41,9 +49,9 @@ impl CounterNode {
 
 impl Node for CounterNode {
     type Payload = CounterPayload;
-    type Event = ();
+    type Event = CounterEvent;
 
-    fn init(&mut self, init: Init) {
+    fn init(&mut self, init: Init, _: Sender<CounterPayload, CounterEvent>) {
         self.id = init.id;
         self.others.extend(
             init.nodes
@@ -53,7 +61,11 @@ impl Node for CounterNode {
         );
     }
 
-    fn message(&mut self, message: Message<CounterPayload>, sender: Sender<CounterPayload>) {
+    fn message(
+        &mut self,
+        message: Message<CounterPayload>,
+        sender: Sender<CounterPayload, CounterEvent>,
+    ) -> Result<(), Error> {
         let dst = message.src;
         let rply = message.body.id;
 
@@ -76,26 +88,80 @@ impl Node for CounterNode {
                 sender.send(dst, rply, CounterPayload::GossipOk { value });
             }
 
-            CounterPayload::GossipOk { value } => {
-                self.others
-                    .entry(dst)
-                    .and_modify(|(_, confirmed)| *confirmed = cmp::max(value, *confirmed));
-            }
-
-            _ => unimplemented!(),
+            _ => return Err(ErrorCode::NotSupported.into()),
         };
+
+        Ok(())
     }
 
-    fn event(&mut self, _: (), sender: Sender<CounterPayload>) {
-        for (n, _) in self.others.iter().filter(|(_, &(_, conf))| conf < self.acc) {
-            sender.send(n.clone(), None, CounterPayload::Gossip { value: self.acc });
+    fn event(&mut self, event: CounterEvent, sender: Sender<CounterPayload, CounterEvent>) {
+        match event {
+            CounterEvent::Gossip => {
+                for (n, _) in self.others.iter().filter(|(_, &(_, conf))| conf < self.acc) {
+                    let node = n.clone();
+                    sender.rpc(
+                        n.clone(),
+                        CounterPayload::Gossip { value: self.acc },
+                        GOSSIP_TIMEOUT,
+                        move |reply| {
+                            // a lost gossip confirms nothing, it is retried on the next round
+                            let value = match reply.map(|reply| reply.body.payload) {
+                                Ok(CounterPayload::GossipOk { value }) => value,
+                                _ => 0,
+                            };
+
+                            CounterEvent::Confirmed { node, value }
+                        },
+                    );
+                }
+            }
+
+            CounterEvent::Confirmed { node, value } => {
+                self.others
+                    .entry(node)
+                    .and_modify(|(_, confirmed)| *confirmed = cmp::max(value, *confirmed));
+            }
         }
     }
 }
 
 fn main() {
     Runtime::new()
-        .event(Duration::from_millis(100), ())
+        .event(Duration::from_millis(100), CounterEvent::Gossip)
         .run(CounterNode::new())
         .unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crabstorm::sim::Sim;
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn counter_converges() {
+        let mut sim = Sim::new(3, 42, CounterNode::new)
+            .event(Duration::from_millis(100), CounterEvent::Gossip);
+
+        for (delta, node) in sim.node_ids().iter().enumerate() {
+            let reply = sim
+                .call(node, CounterPayload::Add { delta }, TIMEOUT)
+                .unwrap();
+            assert!(matches!(reply.body.payload, CounterPayload::AddOk));
+        }
+
+        sim.run_for(Duration::from_secs(1));
+
+        for node in sim.node_ids() {
+            let reply = sim.call(&node, CounterPayload::Read, TIMEOUT).unwrap();
+            assert!(matches!(
+                reply.body.payload,
+                CounterPayload::ReadOk { value: 3 }
+            ));
+        }
+    }
+}
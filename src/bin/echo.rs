@@ -22,14 +22,20 @@ impl Node for EchoNode {
     type Payload = EchoPayload;
     type Event = ();
 
-    fn init(&mut self, _: Init) {}
+    fn init(&mut self, _: Init, _: Sender<EchoPayload>) {}
 
-    fn message(&mut self, message: Message<EchoPayload>, sender: Sender<EchoPayload>) {
+    fn message(
+        &mut self,
+        message: Message<EchoPayload>,
+        sender: Sender<EchoPayload>,
+    ) -> Result<(), Error> {
         let EchoPayload::Echo { echo } = message.body.payload else {
-            panic!("unexpected payload {:?}", message.body.payload);
+            return Err(ErrorCode::NotSupported.into());
         };
 
         sender.send(message.src, message.body.id, EchoPayload::EchoOk { echo });
+
+        Ok(())
     }
 }
 
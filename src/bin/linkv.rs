@@ -2,190 +2,313 @@ use std::{collections::HashMap, time::Duration};
 
 use serde::{Deserialize, Serialize};
 
-use crabstorm::*;
+use crabstorm::{
+    raft::{Keyed, ReplicatedStateMachine, Sharding},
+    *,
+};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
-enum LinkvPayload {
+enum Operation {
     Read { key: Value },
-    ReadOk { value: Option<Value> },
     Write { key: Value, value: Value },
-    WriteOk,
     Cas { key: Value, from: Value, to: Value },
-    CasOk,
-    Error { code: usize },
-    Raft { rpc: raft::Rpc<RaftCommand> },
-}
-
-#[derive(Clone, Debug)]
-enum LinkvEvent {
-    RaftTick,
-    Debug,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
-enum Command {
-    Write { key: Value, value: Value },
-    Cas { key: Value, from: Value, to: Value },
-}
-
-#[derive(Clone, Debug, Serialize, Deserialize)]
-struct RaftCommand {
-    origin: String,
-    reply: (usize, String),
-    command: Command,
+#[serde(tag = "type")]
+enum Reply {
+    #[serde(rename = "read_ok")]
+    Read { value: Option<Value> },
+    #[serde(rename = "write_ok")]
+    Write,
+    #[serde(rename = "cas_ok")]
+    Cas,
 }
 
-const KEY_MISSING: usize = 20;
-const VALUE_MISMATCH: usize = 22;
+// json objects only have string keys, so snapshots keep the store as a list of pairs
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(from = "Vec<(Value, Value)>", into = "Vec<(Value, Value)>")]
+struct Store(HashMap<Value, Value>);
 
-macro_rules! error {
-    ($e:expr) => {
-        LinkvPayload::Error { code: $e }
-    };
+impl From<Vec<(Value, Value)>> for Store {
+    fn from(pairs: Vec<(Value, Value)>) -> Self {
+        Self(pairs.into_iter().collect())
+    }
 }
 
-struct LinkvNode {
-    store: HashMap<Value, Value>,
-    raft: raft::Raft<RaftCommand>,
+impl From<Store> for Vec<(Value, Value)> {
+    fn from(store: Store) -> Self {
+        store.0.into_iter().collect()
+    }
 }
 
-impl LinkvNode {
-    fn new() -> Self {
-        Self {
-            store: HashMap::new(),
-            raft: raft::Raft::new("nop".to_string(), vec![]),
-        }
-    }
+impl raft::Command<Store> for Operation {
+    type Output = Reply;
 
-    fn apply_raft(&mut self, command: RaftCommand, sender: &Sender<LinkvPayload>) {
-        if let Some(delivery) = self.raft.apply(command) {
-            self.send_raft(delivery, sender);
-        }
-    }
+    fn apply(&self, store: &mut Store) -> Result<Reply, Error> {
+        match self {
+            Operation::Read { key } => Ok(Reply::Read {
+                value: store.0.get(key).cloned(),
+            }),
 
-    fn send_raft(&self, delivery: raft::Delivery<RaftCommand>, sender: &Sender<LinkvPayload>) {
-        match delivery {
-            raft::Delivery::Unicast(dest, rpc) => {
-                sender.send(dest, None, LinkvPayload::Raft { rpc });
+            Operation::Write { key, value } => {
+                store.0.insert(key.clone(), value.clone());
+                Ok(Reply::Write)
             }
 
-            raft::Delivery::Broadcast(rpc) => {
-                let payload = LinkvPayload::Raft { rpc };
-                for node in self.raft.others() {
-                    sender.send(node.clone(), None, payload.clone());
-                }
-            }
+            Operation::Cas { key, from, to } => {
+                let Some(entry) = store.0.get_mut(key) else {
+                    return Err(ErrorCode::KeyDoesNotExist.into());
+                };
 
-            raft::Delivery::Multicast(rpcs) => {
-                for (dest, rpc) in rpcs.into_iter() {
-                    sender.send(dest, None, LinkvPayload::Raft { rpc });
+                if entry != from {
+                    return Err(ErrorCode::PreconditionFailed.into());
                 }
+
+                *entry = to.clone();
+                Ok(Reply::Cas)
             }
         }
     }
+
+    fn read_only(&self) -> bool {
+        matches!(self, Operation::Read { .. })
+    }
 }
 
-impl Node for LinkvNode {
-    type Payload = LinkvPayload;
-    type Event = LinkvEvent;
+impl Keyed for Operation {
+    type Key = Value;
 
-    fn init(&mut self, init: Init) {
-        self.raft = raft::Raft::new(init.id, init.nodes);
+    fn key(&self) -> &Value {
+        match self {
+            Operation::Read { key } | Operation::Write { key, .. } | Operation::Cas { key, .. } => {
+                key
+            }
+        }
     }
+}
 
-    fn message(&mut self, message: Message<LinkvPayload>, sender: Sender<LinkvPayload>) {
-        let id = message.body.id;
-        let dest = message.src;
+// how many commands each group applies between snapshots of its part of the store
+const SNAPSHOT_EVERY: usize = 50;
 
-        match message.body.payload {
-            LinkvPayload::Read { key } => {
-                let value = self.store.get(&key).cloned();
-                sender.send(dest, id, LinkvPayload::ReadOk { value });
-            }
+// how many Raft groups the keys are split among, each led by a different node when it can be
+const GROUPS: usize = 3;
 
-            LinkvPayload::Write { key, value } => {
-                let command = RaftCommand {
-                    origin: self.raft.id().clone(),
-                    reply: (id.unwrap(), dest),
-                    command: Command::Write { key, value },
-                };
+type LinkvNode = ReplicatedStateMachine<Store, Operation>;
 
-                self.apply_raft(command, &sender);
-            }
+fn linkv() -> LinkvNode {
+    ReplicatedStateMachine::new(Store::default())
+        .configure(|_, raft| {
+            raft.lease(Duration::from_millis(800))
+                .pre_vote(true)
+                .check_quorum(true)
+        })
+        .shard(Sharding::Hash(GROUPS))
+        .snapshot_every(SNAPSHOT_EVERY)
+}
 
-            LinkvPayload::Cas { key, from, to } => {
-                let command = RaftCommand {
-                    origin: self.raft.id().clone(),
-                    reply: (id.unwrap(), dest),
-                    command: Command::Cas { key, from, to },
-                };
+fn main() {
+    Runtime::new().run(linkv()).unwrap()
+}
 
-                self.apply_raft(command, &sender);
-            }
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
 
-            LinkvPayload::Raft { rpc } => {
-                if let Some(delivery) = self.raft.process(dest, rpc) {
-                    self.send_raft(delivery, &sender);
-                }
+    use crabstorm::{
+        checker::{self, History, Op},
+        sim::{Nemesis, Sim},
+    };
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use serde_json::json;
 
-                while let Some(action) = self.raft.consume() {
-                    let (reply, dest) = action.reply;
+    use super::*;
 
-                    match action.command {
-                        Command::Write { key, value } => {
-                            self.store.insert(key.clone(), value.clone());
+    type LinkvPayload = raft::Replicated<Operation, Reply>;
 
-                            if action.origin == *self.raft.id() {
-                                sender.send(dest, Some(reply), LinkvPayload::WriteOk);
-                            }
-                        }
+    const TIMEOUT: Duration = Duration::from_secs(1);
 
-                        Command::Cas { key, from, to } => {
-                            let mut result = LinkvPayload::CasOk;
-
-                            if let Some(entry) = self.store.get_mut(&key) {
-                                if *entry == from {
-                                    *entry = to.clone();
-                                } else {
-                                    result = error!(VALUE_MISMATCH);
-                                }
-                            } else {
-                                result = error!(KEY_MISSING);
-                            }
+    fn value(value: serde_json::Value) -> Value {
+        serde_json::from_value(value).unwrap()
+    }
 
-                            if action.origin == *self.raft.id() {
-                                sender.send(dest, Some(reply), result);
-                            }
-                        }
-                    }
-                }
+    // runs are fixed by the seed, but a write can still find no leader yet, or be forwarded to a
+    // leader that just crashed and time out before its group elects another: writing the same
+    // value again is harmless either way
+    fn write(
+        sim: &mut Sim<LinkvNode>,
+        node: &str,
+        key: serde_json::Value,
+        to: serde_json::Value,
+    ) -> Message<LinkvPayload> {
+        for _ in 0..10 {
+            let payload = LinkvPayload::Command(Operation::Write {
+                key: value(key.clone()),
+                value: value(to.clone()),
+            });
+
+            match sim.call(node, payload, TIMEOUT) {
+                Ok(reply) => return reply,
+                Err(_) => sim.run_for(Duration::from_millis(500)),
             }
+        }
+
+        panic!("write to {} kept failing", node);
+    }
 
-            _ => unreachable!(),
+    #[test]
+    fn write_is_replicated() {
+        let mut sim = Sim::new(3, 7, linkv);
+
+        // elections only happen once the virtual clock passes the election timeout
+        sim.run_for(Duration::from_secs(5));
+
+        let reply = write(&mut sim, "n1", json!(1), json!(42));
+        assert!(matches!(
+            reply.body.payload,
+            LinkvPayload::Output(Reply::Write)
+        ));
+
+        sim.run_for(Duration::from_secs(1));
+
+        for node in sim.node_ids() {
+            let payload = LinkvPayload::Command(Operation::Read {
+                key: value(json!(1)),
+            });
+            let reply = sim.call(&node, payload, TIMEOUT).unwrap();
+            let LinkvPayload::Output(Reply::Read { value: read }) = reply.body.payload else {
+                panic!("unexpected reply {:?}", reply);
+            };
+
+            assert_eq!(read, Some(value(json!(42))));
         }
     }
 
-    fn event(&mut self, event: Self::Event, sender: Sender<LinkvPayload>) {
-        match event {
-            LinkvEvent::RaftTick => {
-                if let Some(delivery) = self.raft.tick() {
-                    self.send_raft(delivery, &sender);
-                }
-            }
+    #[test]
+    fn lagging_node_gets_snapshot() {
+        let mut sim = Sim::new(3, 5, linkv);
+
+        sim.run_for(Duration::from_secs(5));
+        sim.crash("n2");
+
+        // enough for every group to be compacted a few times over, and sent in more than one chunk
+        let padding = "x".repeat(1000);
+        for key in 0..300 {
+            let reply = write(
+                &mut sim,
+                "n0",
+                json!(key),
+                json!(format!("{}{}", key, padding)),
+            );
+            assert!(matches!(
+                reply.body.payload,
+                LinkvPayload::Output(Reply::Write)
+            ));
+        }
 
-            LinkvEvent::Debug => {
-                eprintln!("STATE: {:?}", self.store);
-            }
+        let logs: usize = (0..GROUPS)
+            .map(|group| sim.node("n0").raft(group).log().len())
+            .sum();
+        assert!(logs < 300);
+
+        // n2 comes back empty, and the entries it misses are gone from the leader's log
+        sim.restart("n2");
+        sim.run_for(Duration::from_secs(5));
+
+        let stored: usize = (0..GROUPS)
+            .map(|group| sim.node("n2").state(group).0.len())
+            .sum();
+        assert!(stored >= 200);
+        let payload = LinkvPayload::Command(Operation::Read {
+            key: value(json!(0)),
+        });
+        let reply = sim.call("n2", payload, TIMEOUT).unwrap();
+        let LinkvPayload::Output(Reply::Read { value: read }) = reply.body.payload else {
+            panic!("unexpected reply {:?}", reply);
         };
+
+        assert_eq!(read, Some(value(json!(format!("0{}", padding)))));
     }
-}
 
-fn main() {
-    Runtime::new()
-        .event(Duration::from_millis(50), LinkvEvent::RaftTick)
-        .event(Duration::from_millis(1000), LinkvEvent::Debug)
-        .run(LinkvNode::new())
-        .unwrap()
+    #[test]
+    fn history_is_linearizable() {
+        const CLIENTS: usize = 4;
+
+        // partitions depose leaders while reads are in flight
+        let mut sim = Sim::new(3, 11, linkv).nemesis(Nemesis::Partition, Duration::from_secs(3));
+        let mut rng = StdRng::seed_from_u64(11);
+
+        sim.run_for(Duration::from_secs(5));
+
+        let mut history = History::new();
+        let mut inflight: Vec<Option<(usize, Duration, Op)>> = vec![None; CLIENTS];
+        let mut completed = 0;
+
+        while completed < 200 {
+            for (process, request) in inflight.iter_mut().enumerate() {
+                let Some((id, deadline, op)) = request.clone() else {
+                    let key = value(json!(rng.gen_range(0..2)));
+                    let (payload, op) = if rng.gen_bool(0.3) {
+                        let payload = LinkvPayload::Command(Operation::Read { key: key.clone() });
+                        (payload, Op::Read(None))
+                    } else if rng.gen_bool(0.5) {
+                        let to = value(json!(rng.gen_range(0..5)));
+                        let payload = LinkvPayload::Command(Operation::Write {
+                            key: key.clone(),
+                            value: to.clone(),
+                        });
+                        (payload, Op::Write(to))
+                    } else {
+                        let from = value(json!(rng.gen_range(0..5)));
+                        let to = value(json!(rng.gen_range(0..5)));
+                        let payload = LinkvPayload::Command(Operation::Cas {
+                            key: key.clone(),
+                            from: from.clone(),
+                            to: to.clone(),
+                        });
+                        (payload, Op::Cas(from, to))
+                    };
+
+                    let node = format!("n{}", rng.gen_range(0..3));
+                    history.invoke(process, key, op.clone());
+                    *request = Some((sim.send(&node, payload), sim.now() + TIMEOUT, op));
+                    continue;
+                };
+
+                if let Some(reply) = sim.reply(id) {
+                    let completed = match reply {
+                        Ok(message) => match message.body.payload {
+                            LinkvPayload::Output(Reply::Read { value }) => {
+                                history.ok(process, Op::Read(value))
+                            }
+                            LinkvPayload::Output(Reply::Write | Reply::Cas) => {
+                                history.ok(process, op)
+                            }
+                            payload => panic!("unexpected reply {:?}", payload),
+                        },
+                        Err(RpcError::Remote(error)) if error.is_definite() => {
+                            history.fail(process)
+                        }
+                        Err(_) => history.info(process),
+                    };
+                    completed.unwrap();
+                } else if sim.now() > deadline {
+                    history.info(process).unwrap();
+                } else {
+                    continue;
+                }
+
+                *request = None;
+                completed += 1;
+            }
+
+            sim.step();
+        }
+
+        if let Err(violation) = checker::check(&history) {
+            panic!("{}", violation);
+        }
+    }
 }
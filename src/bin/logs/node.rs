@@ -1,5 +1,5 @@
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, unreachable};
+use std::collections::HashMap;
 
 use crate::log::Log;
 use crabstorm::*;
@@ -51,11 +51,15 @@ impl Node for LogNode {
     type Payload = LogPayload;
     type Event = ();
 
-    fn init(&mut self, init: Init) {
+    fn init(&mut self, init: Init, _: Sender<LogPayload>) {
         self.id = init.id;
     }
 
-    fn message(&mut self, message: Message<LogPayload>, sender: Sender<LogPayload>) {
+    fn message(
+        &mut self,
+        message: Message<LogPayload>,
+        sender: Sender<LogPayload>,
+    ) -> Result<(), Error> {
         let dst = message.src;
         let rply = message.body.id;
 
@@ -102,7 +106,9 @@ impl Node for LogNode {
                 sender.send(dst, rply, LogPayload::ListCommittedOffsetsOk { offsets });
             }
 
-            _ => unreachable!(),
+            _ => return Err(ErrorCode::NotSupported.into()),
         }
+
+        Ok(())
     }
 }
@@ -35,12 +35,16 @@ impl Node for SetNode {
     type Payload = SetPayload;
     type Event = ();
 
-    fn init(&mut self, init: Init) {
+    fn init(&mut self, init: Init, _: Sender<SetPayload>) {
         self.id = init.id;
         self.neighbors = init.nodes;
     }
 
-    fn message(&mut self, message: Message<SetPayload>, sender: Sender<SetPayload>) {
+    fn message(
+        &mut self,
+        message: Message<SetPayload>,
+        sender: Sender<SetPayload>,
+    ) -> Result<(), Error> {
         let dst = message.src;
         let rply = message.body.id;
 
@@ -61,8 +65,10 @@ impl Node for SetNode {
                 self.set.extend(elements);
             }
 
-            _ => unimplemented!(),
+            _ => return Err(ErrorCode::NotSupported.into()),
         };
+
+        Ok(())
     }
 
     fn event(&mut self, _: (), sender: Sender<SetPayload>) {
@@ -30,9 +30,13 @@ impl Node for KvNode {
     type Payload = KvPayload;
     type Event = ();
 
-    fn init(&mut self, _: Init) {}
+    fn init(&mut self, _: Init, _: Sender<KvPayload>) {}
 
-    fn message(&mut self, message: Message<KvPayload>, sender: Sender<KvPayload>) {
+    fn message(
+        &mut self,
+        message: Message<KvPayload>,
+        sender: Sender<KvPayload>,
+    ) -> Result<(), Error> {
         let dest = message.src;
         let reply = message.body.id;
 
@@ -53,7 +57,9 @@ impl Node for KvNode {
                 sender.send(dest, reply, KvPayload::TxnOk { txn });
             }
 
-            _ => unreachable!(),
+            _ => return Err(ErrorCode::NotSupported.into()),
         }
+
+        Ok(())
     }
 }
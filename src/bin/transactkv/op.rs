@@ -22,7 +22,7 @@ impl Op {
         }
     }
 
-    fn to_raw(&self) -> RawOp {
+    fn to_raw(&self) -> RawOp<'_> {
         match self {
             Op::Read { key, read } => RawOp("r", *key, Either::Right(read.clone())),
             Op::Append { key, value } => RawOp("append", *key, Either::Left(*value)),
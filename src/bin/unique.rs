@@ -23,11 +23,15 @@ impl Node for UniqueNode {
     type Payload = UniquePayload;
     type Event = ();
 
-    fn init(&mut self, _: Init) {}
+    fn init(&mut self, _: Init, _: Sender<UniquePayload>) {}
 
-    fn message(&mut self, message: Message<UniquePayload>, sender: Sender<UniquePayload>) {
+    fn message(
+        &mut self,
+        message: Message<UniquePayload>,
+        sender: Sender<UniquePayload>,
+    ) -> Result<(), Error> {
         let UniquePayload::Generate = message.body.payload else {
-            panic!("unexpected payload {:?}", message.body.payload);
+            return Err(ErrorCode::NotSupported.into());
         };
 
         let id = Ulid::new().to_string();
@@ -37,6 +41,8 @@ impl Node for UniqueNode {
             message.body.id,
             UniquePayload::GenerateOk { id },
         );
+
+        Ok(())
     }
 }
 
@@ -0,0 +1,200 @@
+//! Just enough EDN to read Maelstrom's `history.edn`, mapped onto JSON values: keywords and
+//! symbols become strings, lists and sets become arrays, and tags are dropped.
+
+use anyhow::{bail, Result};
+use serde_json::{Map, Number, Value};
+
+/// Parses every top-level form in `input`.
+pub(crate) fn parse(input: &str) -> Result<Vec<Value>> {
+    let mut parser = Parser {
+        input: input.as_bytes(),
+        pos: 0,
+    };
+
+    let mut forms = Vec::new();
+    while let Some(form) = parser.form()? {
+        forms.push(form);
+    }
+
+    Ok(forms)
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn form(&mut self) -> Result<Option<Value>> {
+        self.skip();
+
+        let Some(byte) = self.peek() else {
+            return Ok(None);
+        };
+
+        let value = match byte {
+            b'[' | b'(' => Value::Array(self.seq(byte)?),
+            b'{' => self.map()?,
+            b'"' => Value::String(self.string()?),
+            b':' => {
+                self.pos += 1;
+                Value::String(self.token().to_string())
+            }
+            b'#' => return self.dispatch(),
+            b']' | b')' | b'}' => bail!("unexpected {} at {}", byte as char, self.pos),
+            _ => self.atom()?,
+        };
+
+        Ok(Some(value))
+    }
+
+    fn dispatch(&mut self) -> Result<Option<Value>> {
+        self.pos += 1;
+
+        match self.peek() {
+            Some(b'{') => Ok(Some(Value::Array(self.seq(b'{')?))),
+            Some(b'_') => {
+                self.pos += 1;
+                self.form()?;
+                self.form()
+            }
+            _ => {
+                // a tagged literal like `#inst "..."`, keep the value only
+                self.token();
+                self.form()
+            }
+        }
+    }
+
+    fn seq(&mut self, open: u8) -> Result<Vec<Value>> {
+        let close = match open {
+            b'[' => b']',
+            b'(' => b')',
+            _ => b'}',
+        };
+
+        self.pos += 1;
+
+        let mut values = Vec::new();
+        loop {
+            self.skip();
+            match self.peek() {
+                Some(byte) if byte == close => {
+                    self.pos += 1;
+                    return Ok(values);
+                }
+                Some(_) => values.extend(self.form()?),
+                None => bail!("unterminated collection"),
+            }
+        }
+    }
+
+    fn map(&mut self) -> Result<Value> {
+        let values = self.seq(b'{')?;
+        if values.len() % 2 != 0 {
+            bail!("map with an odd number of forms");
+        }
+
+        let mut map = Map::new();
+        let mut values = values.into_iter();
+        while let (Some(key), Some(value)) = (values.next(), values.next()) {
+            let key = match key {
+                Value::String(key) => key,
+                key => key.to_string(),
+            };
+
+            map.insert(key, value);
+        }
+
+        Ok(Value::Object(map))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        self.pos += 1;
+
+        let mut string = String::new();
+        loop {
+            let Some(byte) = self.peek() else {
+                bail!("unterminated string");
+            };
+            self.pos += 1;
+
+            match byte {
+                b'"' => return Ok(string),
+                b'\\' => {
+                    let escaped = self.peek().unwrap_or(b'\\');
+                    self.pos += 1;
+
+                    string.push(match escaped {
+                        b'n' => '\n',
+                        b't' => '\t',
+                        b'r' => '\r',
+                        other => other as char,
+                    });
+                }
+                _ => {
+                    // copy multi-byte characters whole
+                    let start = self.pos - 1;
+                    while self.peek().is_some_and(|byte| byte & 0xC0 == 0x80) {
+                        self.pos += 1;
+                    }
+                    string.push_str(std::str::from_utf8(&self.input[start..self.pos])?);
+                }
+            }
+        }
+    }
+
+    fn atom(&mut self) -> Result<Value> {
+        let token = self.token();
+
+        let value = match token {
+            "nil" => Value::Null,
+            "true" => Value::Bool(true),
+            "false" => Value::Bool(false),
+            _ => {
+                let number = token.trim_end_matches(['N', 'M']);
+                if let Ok(int) = number.parse::<i64>() {
+                    Value::Number(int.into())
+                } else if let Some(float) = number.parse().ok().and_then(Number::from_f64) {
+                    Value::Number(float)
+                } else if token.is_empty() {
+                    bail!("unexpected character at {}", self.pos);
+                } else {
+                    Value::String(token.to_string())
+                }
+            }
+        };
+
+        Ok(value)
+    }
+
+    fn token(&mut self) -> &str {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|byte| !byte.is_ascii_whitespace() && !b",()[]{}\";".contains(&byte))
+        {
+            self.pos += 1;
+        }
+
+        std::str::from_utf8(&self.input[start..self.pos]).unwrap_or_default()
+    }
+
+    fn skip(&mut self) {
+        while let Some(byte) = self.peek() {
+            match byte {
+                b';' => {
+                    while self.peek().is_some_and(|byte| byte != b'\n') {
+                        self.pos += 1;
+                    }
+                }
+                byte if byte.is_ascii_whitespace() || byte == b',' => self.pos += 1,
+                _ => return,
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+}
@@ -0,0 +1,197 @@
+use anyhow::{bail, Result};
+use serde_json::Value as Json;
+
+use super::edn;
+use crate::Value;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    Invoke,
+    /// The operation took place.
+    Ok,
+    /// The operation definitely did not take place.
+    Fail,
+    /// The operation may or may not have taken place, e.g. it timed out.
+    Info,
+}
+
+/// An operation on a single register. A `Read` carries the value read, which is only known
+/// once it completes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    Read(Option<Value>),
+    Write(Value),
+    Cas(Value, Value),
+}
+
+impl Op {
+    /// Applies the operation to a register holding `state`, returning the new state, or
+    /// `None` if the operation can't take place from that state.
+    pub(crate) fn step(&self, state: &Option<Value>) -> Option<Option<Value>> {
+        match self {
+            Op::Read(value) => (value == state).then(|| state.clone()),
+            Op::Write(value) => Some(Some(value.clone())),
+            Op::Cas(from, to) => (state.as_ref() == Some(from)).then(|| Some(to.clone())),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub process: usize,
+    pub kind: EventKind,
+    pub key: Value,
+    pub op: Op,
+}
+
+/// A history of operations against a key-value store, in real-time order. Each process has
+/// at most one operation in flight: an `Invoke` followed by its `Ok`, `Fail` or `Info`.
+#[derive(Clone, Debug, Default)]
+pub struct History {
+    events: Vec<Event>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    pub fn push(&mut self, event: Event) {
+        self.events.push(event);
+    }
+
+    pub fn invoke(&mut self, process: usize, key: Value, op: Op) {
+        self.push(Event {
+            process,
+            kind: EventKind::Invoke,
+            key,
+            op,
+        });
+    }
+
+    /// Completes the operation in flight on `process`, with `op` holding what was read, if
+    /// anything. Fails, leaving the history as it was, if `process` has nothing in flight, as
+    /// do `fail` and `info`.
+    pub fn ok(&mut self, process: usize, op: Op) -> Result<()> {
+        self.complete(process, EventKind::Ok, Some(op))
+    }
+
+    pub fn fail(&mut self, process: usize) -> Result<()> {
+        self.complete(process, EventKind::Fail, None)
+    }
+
+    pub fn info(&mut self, process: usize) -> Result<()> {
+        self.complete(process, EventKind::Info, None)
+    }
+
+    /// Parses Maelstrom's `history.edn`.
+    pub fn from_edn(input: &str) -> Result<Self> {
+        // either one map per line, or a single vector of them
+        let forms = match <[Json; 1]>::try_from(edn::parse(input)?) {
+            Ok([Json::Array(events)]) => events,
+            Ok([form]) => vec![form],
+            Err(forms) => forms,
+        };
+
+        Self::from_values(forms)
+    }
+
+    /// Parses a JSON array of events, or one JSON event per line, using the same fields as
+    /// Maelstrom's `history.edn`.
+    pub fn from_json(input: &str) -> Result<Self> {
+        let values = match serde_json::from_str(input) {
+            Ok(Json::Array(values)) => values,
+            _ => input
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(serde_json::from_str)
+                .collect::<Result<_, _>>()?,
+        };
+
+        Self::from_values(values)
+    }
+
+    fn from_values(values: Vec<Json>) -> Result<Self> {
+        let mut history = History::new();
+        for value in values.iter() {
+            if let Some(event) = event(value)? {
+                history.push(event);
+            }
+        }
+
+        Ok(history)
+    }
+
+    fn complete(&mut self, process: usize, kind: EventKind, op: Option<Op>) -> Result<()> {
+        let invoke = self
+            .events
+            .iter()
+            .rev()
+            .find(|event| event.process == process)
+            .filter(|event| event.kind == EventKind::Invoke);
+
+        let Some(invoke) = invoke else {
+            bail!("no operation in flight on process {}", process);
+        };
+
+        let key = invoke.key.clone();
+        let op = op.unwrap_or_else(|| invoke.op.clone());
+
+        self.push(Event {
+            process,
+            kind,
+            key,
+            op,
+        });
+
+        Ok(())
+    }
+}
+
+// lin-kv events look like `{:process 0, :type :ok, :f :cas, :value [key [from to]]}`, anything
+// without a numeric process (e.g. the nemesis) is skipped
+fn event(value: &Json) -> Result<Option<Event>> {
+    let Some(process) = value.get("process").and_then(Json::as_u64) else {
+        return Ok(None);
+    };
+
+    let kind = match value.get("type").and_then(Json::as_str) {
+        Some("invoke") => EventKind::Invoke,
+        Some("ok") => EventKind::Ok,
+        Some("fail") => EventKind::Fail,
+        Some("info") => EventKind::Info,
+        kind => bail!("unknown event type {:?}", kind),
+    };
+
+    let Some(Json::Array(tuple)) = value.get("value") else {
+        bail!("missing [key value] tuple in {}", value);
+    };
+    let [key, argument] = tuple.as_slice() else {
+        bail!("malformed [key value] tuple in {}", value);
+    };
+
+    let op = match value.get("f").and_then(Json::as_str) {
+        Some("read") => Op::Read(Some(argument).filter(|v| !v.is_null()).map(register)),
+        Some("write") => Op::Write(register(argument)),
+        Some("cas") => match argument.as_array().map(Vec::as_slice) {
+            Some([from, to]) => Op::Cas(register(from), register(to)),
+            _ => bail!("malformed cas arguments in {}", value),
+        },
+        f => bail!("unknown function {:?}", f),
+    };
+
+    Ok(Some(Event {
+        process: process as usize,
+        kind,
+        key: register(key),
+        op,
+    }))
+}
+
+fn register(value: &Json) -> Value {
+    serde_json::from_value(value.clone()).expect("Any JSON is a valid value")
+}
@@ -0,0 +1,297 @@
+//! Linearizability checking for lin-kv histories, standing in for Maelstrom's Knossos
+//! checker. Every key is an independent register, so keys are checked one at a time.
+
+mod edn;
+mod history;
+mod wgl;
+
+use std::{collections::HashMap, fmt};
+
+use wgl::Operation;
+
+use crate::Value;
+
+pub use history::{Event, EventKind, History, Op};
+
+/// The shortest prefix of a history that can't be linearized, narrowed down to one key and
+/// to the operations the search could not fit in any order: starting from `state`, no
+/// ordering of `events` respects both real time and the semantics of a register.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    pub key: Value,
+    pub state: Option<Value>,
+    pub events: Vec<Event>,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let json = |value: &Value| serde_json::to_string(value).unwrap_or_default();
+
+        writeln!(
+            f,
+            "key {} is not linearizable from {}:",
+            json(&self.key),
+            self.state.as_ref().map_or("nil".to_string(), json)
+        )?;
+
+        for event in self.events.iter() {
+            let op = match &event.op {
+                Op::Read(value) => format!("read {}", value.as_ref().map_or("nil".into(), json)),
+                Op::Write(value) => format!("write {}", json(value)),
+                Op::Cas(from, to) => format!("cas {} -> {}", json(from), json(to)),
+            };
+
+            writeln!(f, "  {:>4} {:?} {}", event.process, event.kind, op)?;
+        }
+
+        Ok(())
+    }
+}
+
+pub fn check(history: &History) -> Result<(), Violation> {
+    let events = history.events();
+
+    let mut keys = Vec::new();
+    for event in events.iter() {
+        if !keys.contains(&event.key) {
+            keys.push(event.key.clone());
+        }
+    }
+
+    for key in keys {
+        if wgl::check(&operations(events, &key, events.len())).is_err() {
+            return Err(violation(events, key));
+        }
+    }
+
+    Ok(())
+}
+
+fn violation(events: &[Event], key: Value) -> Violation {
+    // a prefix can only be linearized if every shorter one can, so bisect for the shortest
+    // one that can't
+    let (mut ok, mut failed) = (0, events.len());
+    while failed - ok > 1 {
+        let len = (ok + failed) / 2;
+        match wgl::check(&operations(events, &key, len)) {
+            Ok(()) => ok = len,
+            Err(_) => failed = len,
+        }
+    }
+
+    let operations = operations(events, &key, failed);
+    let Err(stuck) = wgl::check(&operations) else {
+        unreachable!("Shortest failing prefix was linearizable");
+    };
+
+    // operations invoked after the blocked one had to return can't help linearizing it
+    let deadline = operations[stuck.blocked].ret.unwrap_or(failed);
+
+    let mut window = stuck
+        .pending
+        .iter()
+        .map(|op| &operations[*op])
+        .filter(|operation| operation.call < deadline)
+        .flat_map(|operation| [Some(operation.call), operation.ret])
+        .flatten()
+        .collect::<Vec<_>>();
+    window.sort();
+
+    Violation {
+        key,
+        state: stuck.state,
+        events: window.into_iter().map(|i| events[i].clone()).collect(),
+    }
+}
+
+// pairs up invocations of `key` with their completions among the first `len` events
+fn operations(events: &[Event], key: &Value, len: usize) -> Vec<Operation> {
+    let mut operations = Vec::new();
+    let mut pending = HashMap::new();
+
+    for (i, event) in events[..len].iter().enumerate() {
+        if event.key != *key {
+            continue;
+        }
+
+        match event.kind {
+            EventKind::Invoke => {
+                pending.insert(event.process, i);
+            }
+
+            EventKind::Ok => {
+                if let Some(call) = pending.remove(&event.process) {
+                    operations.push(Operation {
+                        op: event.op.clone(),
+                        call,
+                        ret: Some(i),
+                    });
+                }
+            }
+
+            EventKind::Fail => {
+                pending.remove(&event.process);
+            }
+
+            EventKind::Info => {
+                if let Some(call) = pending.remove(&event.process) {
+                    operations.extend(indefinite(events, call));
+                }
+            }
+        }
+    }
+
+    let mut pending = pending.into_values().collect::<Vec<_>>();
+    pending.sort();
+    operations.extend(
+        pending
+            .into_iter()
+            .filter_map(|call| indefinite(events, call)),
+    );
+
+    operations
+}
+
+// an operation that may or may not have happened, reads among them change nothing and
+// can be left out
+fn indefinite(events: &[Event], call: usize) -> Option<Operation> {
+    let op = events[call].op.clone();
+    if let Op::Read(_) = op {
+        return None;
+    }
+
+    Some(Operation {
+        op,
+        call,
+        ret: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{check, EventKind, History, Op};
+    use crate::Value;
+
+    fn v(value: i64) -> Value {
+        serde_json::from_value(json!(value)).unwrap()
+    }
+
+    #[test]
+    fn sequential() {
+        let mut history = History::new();
+
+        for (op, result) in [
+            (Op::Read(None), Op::Read(None)),
+            (Op::Write(v(1)), Op::Write(v(1))),
+            (Op::Cas(v(1), v(2)), Op::Cas(v(1), v(2))),
+            (Op::Read(None), Op::Read(Some(v(2)))),
+        ] {
+            history.invoke(0, v(0), op);
+            history.ok(0, result).unwrap();
+        }
+
+        assert_eq!(check(&history), Ok(()));
+    }
+
+    #[test]
+    fn concurrent_and_indefinite() {
+        let mut history = History::new();
+
+        // the read overlaps the write, so it may see either value
+        history.invoke(0, v(0), Op::Write(v(1)));
+        history.invoke(1, v(0), Op::Read(None));
+        history.ok(1, Op::Read(None)).unwrap();
+        history.ok(0, Op::Write(v(1))).unwrap();
+
+        // a failed cas never happened, a timed out write might have
+        history.invoke(0, v(0), Op::Cas(v(1), v(5)));
+        history.fail(0).unwrap();
+        history.invoke(1, v(0), Op::Write(v(3)));
+        history.info(1).unwrap();
+        history.invoke(2, v(0), Op::Read(None));
+        history.ok(2, Op::Read(Some(v(3)))).unwrap();
+
+        // other keys are independent registers
+        history.invoke(0, v(1), Op::Read(None));
+        history.ok(0, Op::Read(None)).unwrap();
+
+        assert_eq!(check(&history), Ok(()));
+    }
+
+    #[test]
+    fn completion_without_invoke_is_rejected() {
+        let mut history = History::new();
+        history.invoke(0, v(0), Op::Write(v(1)));
+        history.ok(0, Op::Write(v(1))).unwrap();
+
+        // a bad log can complete an operation twice, or one that was never invoked
+        assert!(history.info(0).is_err());
+        assert!(history.fail(1).is_err());
+        assert_eq!(history.events().len(), 2);
+    }
+
+    #[test]
+    fn stale_read() {
+        let mut history = History::new();
+
+        history.invoke(0, v(0), Op::Write(v(1)));
+        history.ok(0, Op::Write(v(1))).unwrap();
+        history.invoke(1, v(0), Op::Write(v(2)));
+        history.ok(1, Op::Write(v(2))).unwrap();
+        history.invoke(0, v(0), Op::Read(None));
+        history.ok(0, Op::Read(Some(v(1)))).unwrap();
+        history.invoke(1, v(0), Op::Write(v(3)));
+        history.ok(1, Op::Write(v(3))).unwrap();
+
+        let violation = check(&history).unwrap_err();
+
+        assert_eq!(violation.key, v(0));
+        assert_eq!(violation.state, Some(v(2)));
+
+        let ops = violation
+            .events
+            .iter()
+            .map(|event| (event.kind, event.op.clone()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ops,
+            [
+                (EventKind::Invoke, Op::Read(None)),
+                (EventKind::Ok, Op::Read(Some(v(1)))),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_edn_and_json() {
+        let edn = r#"
+            {:type :invoke, :f :write, :value [0 1], :time 10, :process 0, :index 0}
+            {:type :info, :f :start-partition, :value nil, :process :nemesis, :index 1}
+            {:type :ok, :f :write, :value [0 1], :time 20, :process 0, :index 2}
+            {:type :invoke, :f :cas, :value [0 [1 2]], :time 30, :process 1, :index 3}
+            {:type :fail, :f :cas, :value [0 [1 2]], :error [:precondition-failed "no"], :process 1}
+            {:type :invoke, :f :read, :value [0 nil], :time 50, :process 0, :index 5}
+            {:type :ok, :f :read, :value [0 2], :time 60, :process 0, :index 6}
+        "#;
+
+        let json = r#"[
+            {"type": "invoke", "f": "write", "value": [0, 1], "process": 0},
+            {"type": "ok", "f": "write", "value": [0, 1], "process": 0},
+            {"type": "invoke", "f": "cas", "value": [0, [1, 2]], "process": 1},
+            {"type": "fail", "f": "cas", "value": [0, [1, 2]], "process": 1},
+            {"type": "invoke", "f": "read", "value": [0, null], "process": 0},
+            {"type": "ok", "f": "read", "value": [0, 2], "process": 0}
+        ]"#;
+
+        let edn = History::from_edn(edn).unwrap();
+        let json = History::from_json(json).unwrap();
+
+        assert_eq!(edn.events(), json.events());
+        assert_eq!(edn.events().len(), 6);
+
+        // the cas failed, so nothing could have written 2
+        assert!(check(&edn).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+//! The Wing & Gong search, with Lowe's cache of already explored configurations: walk the
+//! history in real-time order, linearizing any operation whose call has been seen, and
+//! backtrack when reaching the return of an operation that isn't linearized yet.
+
+use std::collections::HashSet;
+
+use super::Op;
+use crate::Value;
+
+/// An operation on one key. `ret` is `None` for operations that may never have completed,
+/// which can be linearized at any point after their call.
+pub(crate) struct Operation {
+    pub op: Op,
+    pub call: usize,
+    pub ret: Option<usize>,
+}
+
+/// The furthest the search got before failing.
+pub(crate) struct Stuck {
+    pub state: Option<Value>,
+    /// Operations that were not linearized.
+    pub pending: Vec<usize>,
+    /// The operation that had to be linearized next, but couldn't be.
+    pub blocked: usize,
+}
+
+const NIL: usize = usize::MAX;
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct Bits(Vec<u64>);
+
+impl Bits {
+    fn new(len: usize) -> Self {
+        Self(vec![0; len.div_ceil(64)])
+    }
+
+    fn set(&mut self, i: usize) {
+        self.0[i / 64] |= 1 << (i % 64);
+    }
+
+    fn clear(&mut self, i: usize) {
+        self.0[i / 64] &= !(1 << (i % 64));
+    }
+
+    fn get(&self, i: usize) -> bool {
+        self.0[i / 64] & (1 << (i % 64)) != 0
+    }
+}
+
+struct Entry {
+    op: usize,
+    call: bool,
+    // index of the return entry, for calls
+    ret: usize,
+}
+
+pub(crate) fn check(operations: &[Operation]) -> Result<(), Stuck> {
+    let mut entries = operations
+        .iter()
+        .enumerate()
+        .flat_map(|(op, operation)| {
+            let ret = operation.ret.unwrap_or(NIL);
+            [(operation.call, op, true), (ret, op, false)]
+        })
+        .collect::<Vec<_>>();
+    entries.sort();
+
+    let mut returns = vec![0; operations.len()];
+    for (i, (_, op, call)) in entries.iter().enumerate() {
+        if !call {
+            returns[*op] = i;
+        }
+    }
+
+    let entries = entries
+        .into_iter()
+        .map(|(_, op, call)| Entry {
+            op,
+            call,
+            ret: returns[op],
+        })
+        .collect::<Vec<_>>();
+
+    // a doubly linked list over the entries, with `head` as sentinel
+    let head = entries.len();
+    let mut next = vec![NIL; head + 1];
+    let mut prev = vec![NIL; head + 1];
+
+    let mut last = head;
+    for (i, prev) in prev.iter_mut().enumerate().take(head) {
+        next[last] = i;
+        *prev = last;
+        last = i;
+    }
+
+    let remove = |next: &mut Vec<usize>, prev: &mut Vec<usize>, i: usize| {
+        next[prev[i]] = next[i];
+        if next[i] != NIL {
+            prev[next[i]] = prev[i];
+        }
+    };
+
+    let restore = |next: &mut Vec<usize>, prev: &mut Vec<usize>, i: usize| {
+        next[prev[i]] = i;
+        if next[i] != NIL {
+            prev[next[i]] = i;
+        }
+    };
+
+    let mut state = None;
+    let mut linearized = Bits::new(operations.len());
+    let mut cache = HashSet::new();
+    let mut stack: Vec<(usize, Option<Value>)> = Vec::new();
+    let mut stuck: Option<Stuck> = None;
+
+    let mut current = next[head];
+    while next[head] != NIL {
+        let entry = &entries[current];
+
+        if entry.call {
+            let op = entry.op;
+
+            if let Some(new) = operations[op].op.step(&state) {
+                let mut candidate = linearized.clone();
+                candidate.set(op);
+
+                if cache.insert((candidate.clone(), new.clone())) {
+                    stack.push((current, std::mem::replace(&mut state, new)));
+                    linearized = candidate;
+
+                    remove(&mut next, &mut prev, current);
+                    remove(&mut next, &mut prev, entry.ret);
+
+                    current = next[head];
+                    continue;
+                }
+            }
+
+            current = next[current];
+        } else if operations[entry.op].ret.is_none() {
+            // returns that never happened come last, so every completed operation is
+            // linearized by now, and the rest may never have taken effect
+            return Ok(());
+        } else {
+            let deeper = stuck
+                .as_ref()
+                .is_none_or(|stuck| stack.len() > operations.len() - stuck.pending.len());
+
+            if deeper {
+                stuck = Some(Stuck {
+                    state: state.clone(),
+                    pending: (0..operations.len())
+                        .filter(|op| !linearized.get(*op))
+                        .collect(),
+                    blocked: entry.op,
+                });
+            }
+
+            let Some((call, previous)) = stack.pop() else {
+                return Err(stuck.unwrap());
+            };
+
+            let op = entries[call].op;
+            linearized.clear(op);
+            state = previous;
+
+            restore(&mut next, &mut prev, entries[call].ret);
+            restore(&mut next, &mut prev, call);
+
+            current = next[call];
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,287 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::BTreeMap,
+    rc::Rc,
+    time::Duration,
+};
+
+use anyhow::Result;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+use tracing::{debug, warn};
+
+use crate::{
+    node::{decode, Callback, Outgoing, RawMessage, RpcError, TimerCallback},
+    Body, Clock, Error, ErrorCode, Init, Message, Node, Sender, Timer, TimerId,
+};
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum InitPayload {
+    Init(Init),
+    InitOk,
+}
+
+enum Action<E> {
+    Callback(TimerCallback<E>),
+    Event(Timer<E>),
+}
+
+/// Drives a single `Node`: assigns message ids, matches replies to pending requests and
+/// fires timers. It knows nothing about where messages come from or go to,
+/// and time is whatever its `Clock` says it is, so it runs the same under the `Runtime`
+/// and the simulator.
+pub(crate) struct Dispatcher<N>
+where
+    N: Node,
+{
+    node: N,
+    clock: Rc<dyn Clock>,
+    id: String,
+    msg_id: usize,
+    sender: flume::Sender<Outgoing<N::Payload, N::Event>>,
+    receiver: flume::Receiver<Outgoing<N::Payload, N::Event>>,
+    pending: BTreeMap<usize, (Duration, Callback<N::Event>)>,
+    timers: BTreeMap<(Duration, TimerId), Action<N::Event>>,
+    timer_id: Rc<Cell<usize>>,
+    intervals: Vec<(Duration, N::Event)>,
+    rng: Rc<RefCell<StdRng>>,
+    outbox: Vec<RawMessage>,
+}
+
+impl<N, P, E> Dispatcher<N>
+where
+    N: Node<Payload = P, Event = E>,
+    P: Serialize + DeserializeOwned,
+    E: Clone,
+{
+    pub fn new(node: N, intervals: Vec<(Duration, E)>, clock: Rc<dyn Clock>) -> Self {
+        let (sender, receiver) = flume::unbounded();
+
+        Self {
+            node,
+            clock,
+            id: String::new(),
+            msg_id: 0,
+            sender,
+            receiver,
+            pending: BTreeMap::new(),
+            timers: BTreeMap::new(),
+            timer_id: Rc::new(Cell::new(0)),
+            intervals,
+            rng: Rc::new(RefCell::new(StdRng::from_entropy())),
+            outbox: Vec::new(),
+        }
+    }
+
+    /// Makes timer jitter, and the seeds handed to the node, reproducible.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.rng = Rc::new(RefCell::new(StdRng::seed_from_u64(seed)));
+        self
+    }
+
+    pub fn node(&self) -> &N {
+        &self.node
+    }
+
+    pub fn init(&mut self, message: RawMessage) -> Result<()> {
+        let init = decode::<InitPayload>(message)
+            .map_err(|err| anyhow::Error::msg(format!("bad init message: {:?}", err)))?;
+
+        let InitPayload::Init(init_payload) = init.body.payload else {
+            return Err(anyhow::Error::msg("bad init message"));
+        };
+
+        self.id = init_payload.id.clone();
+        for (period, event) in std::mem::take(&mut self.intervals) {
+            self.sender().schedule(Timer::every(period, event));
+        }
+
+        self.write(init.src, init.body.id, InitPayload::InitOk);
+
+        self.node.init(init_payload, self.sender());
+        self.flush();
+
+        Ok(())
+    }
+
+    pub fn receive(&mut self, message: RawMessage) {
+        let pending = message.body.reply.and_then(|id| self.pending.remove(&id));
+
+        if let Some((_, callback)) = pending {
+            debug!("Reply");
+            self.event(callback(Ok(message)));
+        } else {
+            debug!("Message");
+            self.deliver(message);
+        }
+    }
+
+    /// Fires every request timeout and timer that is due by now.
+    pub fn tick(&mut self) {
+        let now = self.clock.now();
+
+        let expired = self
+            .pending
+            .iter()
+            .filter(|(_, (deadline, _))| *deadline <= now)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+
+        for id in expired {
+            if let Some((_, callback)) = self.pending.remove(&id) {
+                debug!("Timeout");
+                self.event(callback(Err(RpcError::Timeout)));
+            }
+        }
+
+        while let Some(entry) = self.timers.first_entry() {
+            if entry.key().0 > now {
+                break;
+            }
+
+            let (_, id) = *entry.key();
+            match entry.remove() {
+                Action::Callback(callback) => self.event(callback()),
+
+                Action::Event(timer) => {
+                    debug!("Event");
+                    let event = timer.event.clone();
+
+                    // rescheduled before the handler runs, so that it can cancel it
+                    if let Some(period) = timer.period {
+                        let deadline = now + period + self.jitter(timer.jitter);
+                        self.timers.insert((deadline, id), Action::Event(timer));
+                    }
+
+                    self.event(event);
+                }
+            }
+        }
+    }
+
+    /// The earliest moment at which `tick` has something to do.
+    pub fn deadline(&self) -> Option<Duration> {
+        let pending = self.pending.values().map(|(deadline, _)| *deadline).min();
+        let timer = self.timers.keys().next().map(|(deadline, _)| *deadline);
+
+        [pending, timer].into_iter().flatten().min()
+    }
+
+    pub fn outbox(&mut self) -> Vec<RawMessage> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    fn sender(&self) -> Sender<P, E> {
+        Sender::new(
+            self.sender.clone(),
+            self.clock.clone(),
+            self.timer_id.clone(),
+            self.rng.clone(),
+        )
+    }
+
+    fn jitter(&mut self, jitter: Duration) -> Duration {
+        if jitter.is_zero() {
+            return Duration::ZERO;
+        }
+
+        self.rng.borrow_mut().gen_range(Duration::ZERO..=jitter)
+    }
+
+    fn event(&mut self, event: E) {
+        self.node.event(event, self.sender());
+        self.flush();
+    }
+
+    fn deliver(&mut self, message: RawMessage) {
+        let (src, id) = (message.src.clone(), message.body.id);
+        let kind = message.body.payload.get("type").cloned();
+
+        match decode::<P>(message) {
+            Ok(message) => {
+                if let Err(error) = self.node.message(message, self.sender()) {
+                    debug!("Error {:?}", error);
+                    self.sender().error(src, id, error);
+                }
+            }
+
+            Err(err) => {
+                warn!("Unsupported message {:?} from {}: {:?}", kind, src, err);
+
+                // answering unknown errors with errors could bounce between two nodes forever
+                if id.is_some() && kind != Some(json!("error")) {
+                    let text =
+                        format!("message type {} is not supported", kind.unwrap_or_default());
+                    self.sender()
+                        .error(src, id, Error::new(ErrorCode::NotSupported, text));
+                }
+            }
+        }
+
+        self.flush();
+    }
+
+    fn flush(&mut self) {
+        let now = self.clock.now();
+
+        while let Ok(outgoing) = self.receiver.try_recv() {
+            match outgoing {
+                Outgoing::Message {
+                    dest,
+                    reply,
+                    payload,
+                } => {
+                    self.write(dest, reply, payload);
+                }
+
+                Outgoing::Rpc {
+                    dest,
+                    payload,
+                    timeout,
+                    callback,
+                } => {
+                    let id = self.write(dest, None, payload);
+                    self.pending.insert(id, (now + timeout, callback));
+                }
+
+                Outgoing::Timer { delay, callback } => {
+                    let id = self.sender().timer_id();
+                    self.timers
+                        .insert((now + delay, id), Action::Callback(callback));
+                }
+
+                Outgoing::Schedule { id, timer } => {
+                    let deadline = now + timer.delay + self.jitter(timer.jitter);
+                    self.timers.insert((deadline, id), Action::Event(timer));
+                }
+
+                Outgoing::Cancel(id) => {
+                    self.timers.retain(|(_, timer), _| *timer != id);
+                }
+            }
+        }
+    }
+
+    fn write<M>(&mut self, dst: String, reply: Option<usize>, payload: M) -> usize
+    where
+        M: Serialize,
+    {
+        let id = self.msg_id;
+        self.msg_id += 1;
+
+        self.outbox.push(Message {
+            src: self.id.clone(),
+            dst,
+            body: Body {
+                id: Some(id),
+                reply,
+                payload: serde_json::to_value(payload).expect("Failed to serialize message"),
+            },
+        });
+
+        id
+    }
+}
@@ -1,8 +1,21 @@
+mod async_node;
+pub mod checker;
+mod clock;
+mod dispatcher;
+mod error;
 mod node;
 pub mod raft;
 mod runtime;
+mod services;
+pub mod sim;
+mod timer;
 mod value;
 
-pub use node::{Body, Init, Message, Node, Sender};
+pub use async_node::{Async, AsyncNode, Context, Wakeup};
+pub use clock::{Clock, SystemClock, VirtualClock};
+pub use error::{Error, ErrorCode};
+pub use node::{Body, Init, Message, Node, RpcError, Sender};
 pub use runtime::Runtime;
+pub use services::{Kv, Tso};
+pub use timer::{Timer, TimerId};
 pub use value::Value;
@@ -1,6 +1,16 @@
-use serde::{Deserialize, Serialize};
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+    time::Duration,
+};
 
-#[derive(Debug, Serialize, Deserialize)]
+use rand::{rngs::StdRng, Rng};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{Clock, Error, Timer, TimerId};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Message<Payload> {
     pub src: String,
     #[serde(rename = "dest")]
@@ -8,7 +18,7 @@ pub struct Message<Payload> {
     pub body: Body<Payload>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Body<Payload> {
     #[serde(rename = "msg_id")]
     pub id: Option<usize>,
@@ -30,27 +40,223 @@ pub trait Node {
     type Payload;
     type Event;
 
-    fn init(&mut self, init: Init);
-    fn message(&mut self, message: Message<Self::Payload>, sender: Sender<Self::Payload>);
-    fn event(&mut self, event: Self::Event, sender: Sender<Self::Payload>) {
+    fn init(&mut self, init: Init, sender: Sender<Self::Payload, Self::Event>);
+    fn message(
+        &mut self,
+        message: Message<Self::Payload>,
+        sender: Sender<Self::Payload, Self::Event>,
+    ) -> Result<(), Error>;
+    fn event(&mut self, event: Self::Event, sender: Sender<Self::Payload, Self::Event>) {
         drop(event);
         drop(sender);
         panic!("Unhandled event");
     }
 }
 
-pub struct Sender<P> {
-    inner: flume::Sender<(String, Option<usize>, P)>,
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RpcError {
+    Timeout,
+    Malformed(String),
+    Remote(Error),
+}
+
+pub(crate) type RawMessage = Message<serde_json::Value>;
+
+pub(crate) type Callback<E> = Box<dyn FnOnce(Result<RawMessage, RpcError>) -> E>;
+
+pub(crate) type TimerCallback<E> = Box<dyn FnOnce() -> E>;
+
+// payloads of requests to Maelstrom services are not part of the node's own protocol
+#[derive(Serialize)]
+#[serde(untagged)]
+pub(crate) enum Payload<P> {
+    Typed(P),
+    Raw(serde_json::Value),
 }
 
-impl<P> Sender<P> {
-    pub(crate) fn new(sender: flume::Sender<(String, Option<usize>, P)>) -> Self {
-        Self { inner: sender }
+pub(crate) enum Outgoing<P, E> {
+    Message {
+        dest: String,
+        reply: Option<usize>,
+        payload: Payload<P>,
+    },
+    Rpc {
+        dest: String,
+        payload: Payload<P>,
+        timeout: Duration,
+        callback: Callback<E>,
+    },
+    Timer {
+        delay: Duration,
+        callback: TimerCallback<E>,
+    },
+    Schedule {
+        id: TimerId,
+        timer: Timer<E>,
+    },
+    Cancel(TimerId),
+}
+
+pub struct Sender<P, E = ()> {
+    inner: flume::Sender<Outgoing<P, E>>,
+    clock: Rc<dyn Clock>,
+    timer_id: Rc<Cell<usize>>,
+    rng: Rc<RefCell<StdRng>>,
+}
+
+impl<P, E> Clone for Sender<P, E> {
+    fn clone(&self) -> Self {
+        Self::new(
+            self.inner.clone(),
+            self.clock.clone(),
+            self.timer_id.clone(),
+            self.rng.clone(),
+        )
+    }
+}
+
+impl<P, E> Sender<P, E> {
+    pub(crate) fn new(
+        sender: flume::Sender<Outgoing<P, E>>,
+        clock: Rc<dyn Clock>,
+        timer_id: Rc<Cell<usize>>,
+        rng: Rc<RefCell<StdRng>>,
+    ) -> Self {
+        Self {
+            inner: sender,
+            clock,
+            timer_id,
+            rng,
+        }
+    }
+
+    /// The clock driving this node, real under the `Runtime` and virtual in the simulator.
+    pub fn clock(&self) -> Rc<dyn Clock> {
+        self.clock.clone()
+    }
+
+    pub fn now(&self) -> Duration {
+        self.clock.now()
+    }
+
+    /// A seed for any randomness the node needs, drawn from the same rng as timer jitter, so
+    /// it's fixed by the simulator's seed and random under the `Runtime`.
+    pub fn seed(&self) -> u64 {
+        self.rng.borrow_mut().gen()
     }
 
     pub fn send(&self, dest: String, reply: Option<usize>, payload: P) {
-        self.inner
-            .send((dest, reply, payload))
-            .expect("Failed to send message");
+        self.push(Outgoing::Message {
+            dest,
+            reply,
+            payload: Payload::Typed(payload),
+        });
+    }
+
+    /// Replies with a Maelstrom `error` message instead of a regular payload.
+    pub fn error(&self, dest: String, reply: Option<usize>, error: Error) {
+        self.push(Outgoing::Message {
+            dest,
+            reply,
+            payload: Payload::Raw(
+                json!({ "type": "error", "code": error.code, "text": error.text }),
+            ),
+        });
+    }
+
+    /// Sends `payload` to `dest` and waits for the message carrying a matching `in_reply_to`.
+    /// The reply (or a timeout, if nothing arrives in time) is turned into an event by
+    /// `callback` and delivered through `Node::event`, instead of `Node::message`.
+    pub fn rpc<F>(&self, dest: String, payload: P, timeout: Duration, callback: F)
+    where
+        P: DeserializeOwned,
+        F: FnOnce(Result<Message<P>, RpcError>) -> E + 'static,
+    {
+        self.request(dest, Payload::Typed(payload), timeout, move |reply| {
+            callback(reply.and_then(decode_reply))
+        });
+    }
+
+    /// Delivers `timer.event` through `Node::event` once the timer fires.
+    pub fn schedule(&self, timer: Timer<E>) -> TimerId {
+        let id = self.timer_id();
+        self.push(Outgoing::Schedule { id, timer });
+        id
+    }
+
+    /// Stops a timer from firing again. Cancelling a timer that already fired, or was
+    /// cancelled before, does nothing.
+    pub fn cancel(&self, id: TimerId) {
+        self.push(Outgoing::Cancel(id));
+    }
+
+    pub(crate) fn timer_id(&self) -> TimerId {
+        let id = self.timer_id.get();
+        self.timer_id.set(id + 1);
+        TimerId(id)
     }
+
+    pub(crate) fn request<F>(
+        &self,
+        dest: String,
+        payload: Payload<P>,
+        timeout: Duration,
+        callback: F,
+    ) where
+        F: FnOnce(Result<RawMessage, RpcError>) -> E + 'static,
+    {
+        self.push(Outgoing::Rpc {
+            dest,
+            payload,
+            timeout,
+            callback: Box::new(callback),
+        });
+    }
+
+    pub(crate) fn after<F>(&self, delay: Duration, callback: F)
+    where
+        F: FnOnce() -> E + 'static,
+    {
+        self.push(Outgoing::Timer {
+            delay,
+            callback: Box::new(callback),
+        });
+    }
+
+    fn push(&self, outgoing: Outgoing<P, E>) {
+        self.inner.send(outgoing).expect("Failed to send message");
+    }
+}
+
+pub(crate) fn decode<P>(message: RawMessage) -> Result<Message<P>, RpcError>
+where
+    P: DeserializeOwned,
+{
+    let payload = serde_json::from_value(message.body.payload)
+        .map_err(|err| RpcError::Malformed(err.to_string()))?;
+
+    Ok(Message {
+        src: message.src,
+        dst: message.dst,
+        body: Body {
+            id: message.body.id,
+            reply: message.body.reply,
+            payload,
+        },
+    })
+}
+
+// like `decode`, but a reply can also be an `error` message, which is not part of `P`
+pub(crate) fn decode_reply<P>(message: RawMessage) -> Result<Message<P>, RpcError>
+where
+    P: DeserializeOwned,
+{
+    if message.body.payload.get("type") == Some(&json!("error")) {
+        let error = serde_json::from_value(message.body.payload)
+            .map_err(|err| RpcError::Malformed(err.to_string()))?;
+
+        return Err(RpcError::Remote(error));
+    }
+
+    decode(message)
 }
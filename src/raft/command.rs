@@ -1,7 +1,32 @@
+use crate::Error;
+
+/// A change to a state machine `S`, which every replica applies in log order.
 pub trait Command<S> {
-    fn apply(&self, state: &mut S);
+    /// What the client that sent the command gets back.
+    type Output;
+
+    /// Must be deterministic: replicas that apply the same commands end up in the same state.
+    fn apply(&self, state: &mut S) -> Result<Self::Output, Error>;
+
+    /// Whether the command leaves the state as it is, so it can be served with a linearizable
+    /// read instead of going through the log.
+    fn read_only(&self) -> bool {
+        false
+    }
 }
 
 impl<S> Command<S> for () {
-    fn apply(&self, _state: &mut S) {}
+    type Output = ();
+
+    fn apply(&self, _state: &mut S) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// A command that reads or writes a single key, so that it can be routed to the Raft group
+/// that owns the key.
+pub trait Keyed {
+    type Key;
+
+    fn key(&self) -> &Self::Key;
 }
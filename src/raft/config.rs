@@ -0,0 +1,30 @@
+use std::{ops::Range, time::Duration};
+
+/// Timing and batching knobs for a `Raft` node.
+#[derive(Clone, Debug)]
+pub struct RaftConfig {
+    /// Followers start an election after hearing nothing for a timeout picked at random from
+    /// this range, anew every time.
+    pub election_timeout: Range<Duration>,
+    /// How often the leader sends appends when it has nothing new, to keep its followers.
+    pub heartbeat_interval: Duration,
+    /// The most entries in one append.
+    pub max_entries: usize,
+    /// Roughly the most bytes of entries in one append, or of snapshot in one chunk. An entry
+    /// bigger than this is still sent, on its own.
+    pub max_bytes: usize,
+    /// The most appends with entries the leader sends a follower before hearing back.
+    pub max_in_flight: usize,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        Self {
+            election_timeout: Duration::from_millis(1000)..Duration::from_millis(2000),
+            heartbeat_interval: Duration::from_millis(50),
+            max_entries: 64,
+            max_bytes: 64 * 1024,
+            max_in_flight: 4,
+        }
+    }
+}
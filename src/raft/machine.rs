@@ -0,0 +1,912 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Debug,
+    hash::Hash,
+    ops::Range,
+    time::Duration,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::{info_span, Span};
+
+use super::{Command, Committed, Delivery, Keyed, Proposal, Raft, RaftConfig, Rpc, Sharding};
+use crate::{Error, ErrorCode, Init, Message, Node, Sender, Timer};
+
+/// A command in the log, with the request to answer once it's applied.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Request<C> {
+    /// The node the client sent it to, which is the one to reply.
+    pub origin: String,
+    pub reply: (usize, String),
+    /// The client that sent it and its number in the client's session, if it has one.
+    pub session: Option<(String, u64)>,
+    pub command: C,
+}
+
+/// A command numbered within its client's session. Every new command must get a higher
+/// number than the last, and a retry the same one: it then gets the output of the first
+/// attempt instead of being applied again. A command numbered lower than one already applied
+/// fails with `PreconditionFailed`.
+///
+/// Each group only keeps so many sessions, and those that were used least recently make room
+/// for new ones. A group remembers the last number applied in as many sessions again as it
+/// dropped, and fails a retry of that command or an earlier one with `PreconditionFailed`, as
+/// it can no longer tell what it output, while a higher number carries on with the session.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Session<C> {
+    pub seq: u64,
+    #[serde(flatten)]
+    pub command: C,
+}
+
+/// What a `ReplicatedStateMachine` sends and receives: commands from clients, with or without
+/// a session, their outputs, and the Raft traffic between replicas.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Replicated<C, O> {
+    Raft(Peer<C>),
+    Session(Session<C>),
+    Command(C),
+    Output(O),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum Peer<C> {
+    /// Everything one node had for another at once, across all groups, so that the heartbeats
+    /// of every group go out as a single message.
+    Raft { rpcs: Vec<Envelope<C>> },
+}
+
+/// An rpc between the replicas of one Raft group.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Envelope<C> {
+    pub group: usize,
+    pub rpc: Rpc<Request<C>>,
+}
+
+/// Drives Raft's timers.
+#[derive(Clone, Copy, Debug)]
+pub struct Tick;
+
+type Configure<C> = Box<dyn Fn(usize, Raft<Request<C>>) -> Raft<Request<C>>>;
+
+type Route<C> = Box<dyn Fn(&C) -> usize>;
+
+// the last command applied in each client's session, with its output and when the session was
+// last used, counted in session commands applied, so that every replica evicts the same ones
+#[derive(Serialize, Deserialize)]
+struct Sessions<O> {
+    clients: HashMap<String, (u64, Result<O, Error>, u64)>,
+    // the last command applied in the sessions evicted most recently, and when they were
+    evicted: HashMap<String, (u64, u64)>,
+    used: u64,
+}
+
+impl<O> Sessions<O> {
+    fn new() -> Self {
+        Self {
+            clients: HashMap::new(),
+            evicted: HashMap::new(),
+            used: 0,
+        }
+    }
+
+    fn output(&self, client: &str, seq: u64) -> Option<&Result<O, Error>> {
+        let (last, output, _) = self.clients.get(client)?;
+        (*last == seq).then_some(output)
+    }
+
+    // drops the least recently used sessions beyond the first `max`, remembering where as many
+    // of them again stopped
+    fn evict(&mut self, max: usize) {
+        while self.clients.len() > max {
+            let oldest = self.clients.iter().min_by_key(|(_, (_, _, used))| *used);
+            let client = oldest.map(|(client, _)| client.clone()).unwrap();
+            let (last, _, _) = self.clients.remove(&client).unwrap();
+            self.evicted.insert(client, (last, self.used));
+        }
+
+        while self.evicted.len() > max {
+            let oldest = self.evicted.iter().min_by_key(|(_, (_, evicted))| *evicted);
+            let client = oldest.map(|(client, _)| client.clone()).unwrap();
+            self.evicted.remove(&client);
+        }
+    }
+}
+
+// what snapshots hold, as sessions have to survive compaction as much as the state does
+#[derive(Serialize, Deserialize)]
+struct Image<S, T> {
+    state: S,
+    sessions: T,
+}
+
+// what the groups have to send after handling a message or a tick, gathered so that rpcs for
+// the same node go out together
+struct Outbox<C, O> {
+    rpcs: BTreeMap<String, Vec<Envelope<C>>>,
+    replies: Vec<(String, usize, Result<O, Error>)>,
+}
+
+impl<C, O> Outbox<C, O> {
+    fn new() -> Self {
+        Self {
+            rpcs: BTreeMap::new(),
+            replies: Vec::new(),
+        }
+    }
+
+    fn reply(&mut self, dest: String, reply: usize, output: Result<O, Error>) {
+        self.replies.push((dest, reply, output));
+    }
+
+    fn send(&mut self, sender: &Sender<Replicated<C, O>, Tick>) {
+        for (dest, rpcs) in std::mem::take(&mut self.rpcs) {
+            sender.send(dest, None, Replicated::Raft(Peer::Raft { rpcs }));
+        }
+
+        for (dest, reply, output) in self.replies.drain(..) {
+            match output {
+                Ok(output) => sender.send(dest, Some(reply), Replicated::Output(output)),
+                Err(error) => sender.error(dest, Some(reply), error),
+            }
+        }
+    }
+}
+
+// one Raft group, with its part of the state
+struct Group<S, C>
+where
+    C: Command<S>,
+{
+    id: usize,
+    state: S,
+    sessions: Sessions<C::Output>,
+    max_sessions: usize,
+    raft: Raft<Request<C>>,
+    applied: usize,
+    // reads waiting on raft, with the request to reply to
+    reads: HashMap<u64, (usize, String, C)>,
+    next_read: u64,
+    // commands proposed to raft, with the request to reply to if they're lost
+    proposals: HashMap<u64, (usize, String)>,
+    next_proposal: u64,
+    // tells apart what the groups' Raft instances trace
+    span: Span,
+}
+
+impl<S, C> Group<S, C>
+where
+    S: Serialize + DeserializeOwned + 'static,
+    C: Command<S> + Clone + Debug + Serialize + 'static,
+    C::Output: Clone + Serialize + DeserializeOwned,
+{
+    fn new(id: usize, state: S, max_sessions: usize, raft: Raft<Request<C>>) -> Self {
+        Self {
+            id,
+            state,
+            sessions: Sessions::new(),
+            max_sessions,
+            raft,
+            applied: 0,
+            reads: HashMap::new(),
+            next_read: 0,
+            proposals: HashMap::new(),
+            next_proposal: 0,
+            span: info_span!("group", id),
+        }
+    }
+
+    fn consume(&mut self, snapshot_every: Option<usize>, outbox: &mut Outbox<C, C::Output>) {
+        while let Some(committed) = self.raft.consume() {
+            match committed {
+                Committed::Command(request) => {
+                    let output = self.execute(request.session, &request.command);
+                    self.applied += 1;
+
+                    if request.origin == *self.raft.id() {
+                        let (reply, dest) = request.reply;
+                        outbox.reply(dest, reply, output);
+                    }
+                }
+
+                Committed::Snapshot(data) => {
+                    let image: Image<S, Sessions<C::Output>> =
+                        serde_json::from_slice(&data).expect("Corrupted snapshot");
+                    self.state = image.state;
+                    self.sessions = image.sessions;
+                }
+
+                Committed::Read(read) => {
+                    if let Some((reply, dest, command)) = self.reads.remove(&read) {
+                        let output = command.apply(&mut self.state);
+                        outbox.reply(dest, reply, output);
+                    }
+                }
+
+                Committed::ReadFailed(read) => {
+                    if let Some((reply, dest, _)) = self.reads.remove(&read) {
+                        let code = ErrorCode::TemporarilyUnavailable;
+                        outbox.reply(dest, reply, Err(code.into()));
+                    }
+                }
+
+                // applying the command already replied
+                Committed::Applied(proposal) => {
+                    self.proposals.remove(&proposal);
+                }
+
+                Committed::Lost(proposal) => {
+                    if let Some((reply, dest)) = self.proposals.remove(&proposal) {
+                        let code = ErrorCode::TemporarilyUnavailable;
+                        outbox.reply(dest, reply, Err(code.into()));
+                    }
+                }
+
+                // it may still be applied, so the client can't take it as failed
+                Committed::Unknown(proposal) => {
+                    if let Some((reply, dest)) = self.proposals.remove(&proposal) {
+                        outbox.reply(dest, reply, Err(ErrorCode::Timeout.into()));
+                    }
+                }
+            }
+        }
+
+        if snapshot_every.is_some_and(|commands| self.applied >= commands) {
+            let image = Image {
+                state: &self.state,
+                sessions: &self.sessions,
+            };
+            let data = serde_json::to_vec(&image).expect("Failed to serialize state");
+            self.raft.snapshot(data);
+            self.applied = 0;
+        }
+    }
+
+    // applies a command once per session number, so a retry gets the output of the first
+    // attempt, and one the client already moved on from an error
+    fn execute(&mut self, session: Option<(String, u64)>, command: &C) -> Result<C::Output, Error> {
+        let Some((client, seq)) = session else {
+            return command.apply(&mut self.state);
+        };
+
+        self.sessions.used += 1;
+        let now = self.sessions.used;
+        match self.sessions.clients.get_mut(&client) {
+            Some((last, output, used)) if *last == seq => {
+                *used = now;
+                return output.clone();
+            }
+            Some((last, _, _)) if *last > seq => {
+                let text = "a later command in the session was applied";
+                return Err(Error::new(ErrorCode::PreconditionFailed, text));
+            }
+            Some(_) => {}
+            None => match self.sessions.evicted.get(&client) {
+                Some((last, _)) if *last >= seq => {
+                    let text = "session expired";
+                    return Err(Error::new(ErrorCode::PreconditionFailed, text));
+                }
+                Some(_) => {
+                    self.sessions.evicted.remove(&client);
+                }
+                None => {}
+            },
+        }
+
+        let output = command.apply(&mut self.state);
+        let session = (seq, output.clone(), now);
+        self.sessions.clients.insert(client, session);
+        self.sessions.evict(self.max_sessions);
+        output
+    }
+
+    fn request(
+        &mut self,
+        reply: usize,
+        dest: String,
+        seq: Option<u64>,
+        command: C,
+        outbox: &mut Outbox<C, C::Output>,
+    ) {
+        if command.read_only() {
+            let read = self.next_read;
+            self.next_read += 1;
+            self.reads.insert(read, (reply, dest, command));
+
+            if let Some(delivery) = self.raft.read(read) {
+                self.deliver(delivery, outbox);
+            }
+
+            return;
+        }
+
+        if let Some(seq) = seq {
+            // no need to go through the log again if this node already applied it
+            if let Some(output) = self.sessions.output(&dest, seq) {
+                outbox.reply(dest, reply, output.clone());
+                return;
+            }
+        }
+
+        let request = Request {
+            origin: self.raft.id().clone(),
+            reply: (reply, dest.clone()),
+            session: seq.map(|seq| (dest, seq)),
+            command,
+        };
+
+        self.propose(request, outbox);
+    }
+
+    fn propose(&mut self, request: Request<C>, outbox: &mut Outbox<C, C::Output>) {
+        let proposal = self.next_proposal;
+        self.next_proposal += 1;
+
+        let (reply, dest) = request.reply.clone();
+        match self.raft.apply(proposal, request) {
+            Proposal::Rejected(_) => {
+                let code = ErrorCode::TemporarilyUnavailable;
+                outbox.reply(dest, reply, Err(code.into()));
+            }
+
+            proposed => {
+                self.proposals.insert(proposal, (reply, dest));
+                if let Some(delivery) = proposed.delivery() {
+                    self.deliver(delivery, outbox);
+                }
+            }
+        }
+    }
+
+    fn deliver(&self, delivery: Delivery<Request<C>>, outbox: &mut Outbox<C, C::Output>) {
+        let rpcs = match delivery {
+            Delivery::Unicast(dest, rpc) => vec![(dest, rpc)],
+            Delivery::Broadcast(rpc) => self
+                .raft
+                .others()
+                .map(|node| (node.clone(), rpc.clone()))
+                .collect(),
+            Delivery::Multicast(rpcs) => rpcs,
+        };
+
+        for (dest, rpc) in rpcs {
+            let envelope = Envelope {
+                group: self.id,
+                rpc,
+            };
+            outbox.rpcs.entry(dest).or_default().push(envelope);
+        }
+    }
+}
+
+/// Replicates a state machine `S` with Raft, so that a node only has to define its commands.
+/// Clients send a `C` to any node, which replies with its output once the command is applied,
+/// or `TemporarilyUnavailable` if it couldn't get it in the log. Sending it in a `Session`
+/// makes retrying it safe.
+///
+/// The state can be sharded over several Raft groups, each replicating its own copy of `S`
+/// that only sees the commands for its keys. Every node is a member of every group, and each
+/// group prefers a different node as its leader, so that the load of leading is spread out.
+pub struct ReplicatedStateMachine<S, C>
+where
+    C: Command<S>,
+{
+    initial: S,
+    groups: Vec<Group<S, C>>,
+    count: usize,
+    route: Route<C>,
+    settings: RaftConfig,
+    configure: Configure<C>,
+    snapshot_every: Option<usize>,
+    max_sessions: usize,
+}
+
+impl<S, C> ReplicatedStateMachine<S, C>
+where
+    S: Clone + Serialize + DeserializeOwned + 'static,
+    C: Command<S> + Clone + Debug + Serialize + 'static,
+    C::Output: Clone + Serialize + DeserializeOwned,
+{
+    /// Starts every group from `state`, until a snapshot replaces it.
+    pub fn new(state: S) -> Self {
+        Self {
+            initial: state,
+            groups: Vec::new(),
+            count: 1,
+            route: Box::new(|_| 0),
+            settings: RaftConfig::default(),
+            configure: Box::new(|_, raft| raft),
+            snapshot_every: None,
+            max_sessions: 1000,
+        }
+    }
+
+    pub fn settings(mut self, settings: RaftConfig) -> Self {
+        self.settings = settings;
+        self
+    }
+
+    /// Sets up the Raft instance of each group once the node knows its id, e.g. to turn on
+    /// leases or give it storage.
+    pub fn configure(
+        mut self,
+        configure: impl Fn(usize, Raft<Request<C>>) -> Raft<Request<C>> + 'static,
+    ) -> Self {
+        self.configure = Box::new(configure);
+        self
+    }
+
+    /// Splits the state among Raft groups, sending each command to the group owning its key.
+    /// Panics if `sharding` isn't valid, see `Sharding::validate`.
+    pub fn shard(mut self, sharding: Sharding<C::Key>) -> Self
+    where
+        C: Keyed,
+        C::Key: Hash + Ord + 'static,
+    {
+        sharding.validate();
+        self.count = sharding.groups();
+        self.route = Box::new(move |command| sharding.group(command.key()));
+        self
+    }
+
+    /// Compacts each group's log into a snapshot of its state every `commands` it applied.
+    pub fn snapshot_every(mut self, commands: usize) -> Self {
+        self.snapshot_every = Some(commands);
+        self
+    }
+
+    /// Keeps at most `sessions` client sessions in each group, 1000 unless set.
+    pub fn max_sessions(mut self, sessions: usize) -> Self {
+        self.max_sessions = sessions;
+        self
+    }
+
+    pub fn groups(&self) -> usize {
+        self.groups.len()
+    }
+
+    pub fn state(&self, group: usize) -> &S {
+        &self.groups[group].state
+    }
+
+    pub fn raft(&self, group: usize) -> &Raft<Request<C>> {
+        &self.groups[group].raft
+    }
+
+    // the range `group` draws election timeouts from on this node, the lower half of the
+    // usual one on its preferred leader, so that it usually wins, and the upper half elsewhere
+    fn election_timeout(&self, group: usize, init: &Init) -> Range<Duration> {
+        let Range { start, end } = self.settings.election_timeout.clone();
+        if self.count == 1 {
+            return start..end;
+        }
+
+        let middle = start + (end - start) / 2;
+        if init.nodes[group % init.nodes.len()] == init.id {
+            start..middle
+        } else {
+            middle..end
+        }
+    }
+}
+
+impl<S, C> Node for ReplicatedStateMachine<S, C>
+where
+    S: Clone + Serialize + DeserializeOwned + 'static,
+    C: Command<S> + Clone + Debug + Serialize + 'static,
+    C::Output: Clone + Serialize + DeserializeOwned,
+{
+    type Payload = Replicated<C, C::Output>;
+    type Event = Tick;
+
+    fn init(&mut self, init: Init, sender: Sender<Self::Payload, Tick>) {
+        self.groups = (0..self.count)
+            .map(|group| {
+                let settings = RaftConfig {
+                    election_timeout: self.election_timeout(group, &init),
+                    ..self.settings.clone()
+                };
+
+                let raft = Raft::new(init.id.clone(), init.nodes.clone(), settings)
+                    .clock(sender.clock())
+                    .seed(sender.seed());
+                let raft = (self.configure)(group, raft);
+                Group::new(group, self.initial.clone(), self.max_sessions, raft)
+            })
+            .collect();
+
+        sender.schedule(Timer::every(self.settings.heartbeat_interval, Tick));
+    }
+
+    fn message(
+        &mut self,
+        message: Message<Self::Payload>,
+        sender: Sender<Self::Payload, Tick>,
+    ) -> Result<(), Error> {
+        let dest = message.src;
+        let mut outbox = Outbox::new();
+
+        let (seq, command) = match message.body.payload {
+            Replicated::Raft(Peer::Raft { rpcs }) => {
+                for Envelope { group, rpc } in rpcs {
+                    let Some(group) = self.groups.get_mut(group) else {
+                        return Err(ErrorCode::MalformedRequest.into());
+                    };
+
+                    let span = group.span.clone();
+                    let _entered = span.enter();
+                    if let Some(delivery) = group.raft.process(dest.clone(), rpc) {
+                        group.deliver(delivery, &mut outbox);
+                    }
+                }
+
+                for group in self.groups.iter_mut() {
+                    group.consume(self.snapshot_every, &mut outbox);
+                }
+
+                outbox.send(&sender);
+                return Ok(());
+            }
+
+            Replicated::Session(Session { seq, command }) => (Some(seq), command),
+            Replicated::Command(command) => (None, command),
+            Replicated::Output(_) => return Err(ErrorCode::NotSupported.into()),
+        };
+
+        let reply = message.body.id.ok_or(ErrorCode::MalformedRequest)?;
+        let group = &mut self.groups[(self.route)(&command)];
+        group.request(reply, dest, seq, command, &mut outbox);
+        group.consume(self.snapshot_every, &mut outbox);
+
+        outbox.send(&sender);
+        Ok(())
+    }
+
+    fn event(&mut self, _tick: Tick, sender: Sender<Self::Payload, Tick>) {
+        let mut outbox = Outbox::new();
+
+        for group in self.groups.iter_mut() {
+            let span = group.span.clone();
+            let _entered = span.enter();
+            if let Some(delivery) = group.raft.tick() {
+                group.deliver(delivery, &mut outbox);
+            }
+
+            group.consume(self.snapshot_every, &mut outbox);
+        }
+
+        outbox.send(&sender);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, fmt::Debug, time::Duration};
+
+    use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+    use super::{Group, Replicated, ReplicatedStateMachine, Session, Tick};
+    use crate::{
+        raft::{Command, Keyed, Raft, RaftConfig, Role, Sharding},
+        sim::{Partition, Sim},
+        Error, ErrorCode, Node, RpcError,
+    };
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "snake_case")]
+    enum Counter {
+        Add { delta: i64 },
+        Read,
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "snake_case")]
+    enum CounterOk {
+        AddOk { value: i64 },
+        ReadOk { value: i64 },
+    }
+
+    impl Command<i64> for Counter {
+        type Output = CounterOk;
+
+        fn apply(&self, state: &mut i64) -> Result<CounterOk, Error> {
+            match self {
+                Counter::Add { delta } if *state + delta < 0 => {
+                    Err(ErrorCode::PreconditionFailed.into())
+                }
+                Counter::Add { delta } => {
+                    *state += delta;
+                    Ok(CounterOk::AddOk { value: *state })
+                }
+                Counter::Read => Ok(CounterOk::ReadOk { value: *state }),
+            }
+        }
+
+        fn read_only(&self) -> bool {
+            matches!(self, Counter::Read)
+        }
+    }
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    type Machine = ReplicatedStateMachine<i64, Counter>;
+
+    // a command that finds no leader, or gets lost in a late election, definitely had no
+    // effect, so it's retried once the cluster had time to settle. With a session, so is one
+    // that timed out or whose fate is unknown, as it's applied once however many times it's
+    // sent
+    fn call<N, C, O>(sim: &mut Sim<N>, node: &str, payload: Replicated<C, O>) -> Result<O, Error>
+    where
+        N: Node<Payload = Replicated<C, O>, Event = Tick>,
+        C: Clone + Debug + Serialize + DeserializeOwned,
+        O: Clone + Debug + Serialize + DeserializeOwned,
+    {
+        let session = matches!(payload, Replicated::Session(_));
+        for _ in 0..10 {
+            match sim.call(node, payload.clone(), TIMEOUT) {
+                Ok(reply) => match reply.body.payload {
+                    Replicated::Output(output) => return Ok(output),
+                    payload => panic!("unexpected reply {:?}", payload),
+                },
+                Err(RpcError::Remote(error)) if error.code == ErrorCode::TemporarilyUnavailable => {
+                    sim.run_for(Duration::from_millis(500))
+                }
+                Err(RpcError::Remote(error)) if error.code == ErrorCode::Timeout && session => {}
+                Err(RpcError::Remote(error)) => return Err(error),
+                Err(RpcError::Timeout) if session => {}
+                Err(error) => panic!("{} failed: {:?}", node, error),
+            }
+        }
+
+        panic!("{} kept failing", node);
+    }
+
+    #[test]
+    fn commands_are_applied_everywhere() {
+        let mut sim = Sim::new(3, 3, || Machine::new(0).snapshot_every(5));
+        sim.run_for(Duration::from_secs(5));
+
+        for (i, node) in ["n0", "n1", "n2", "n0", "n1", "n2"].into_iter().enumerate() {
+            let payload = Replicated::Command(Counter::Add { delta: 1 });
+            let output = call(&mut sim, node, payload).unwrap();
+            assert!(matches!(output, CounterOk::AddOk { value } if value == i as i64 + 1));
+        }
+
+        // outputs can be errors too, and the command then changes nothing
+        let payload = Replicated::Command(Counter::Add { delta: -10 });
+        let error = call(&mut sim, "n1", payload).unwrap_err();
+        assert_eq!(error.code, ErrorCode::PreconditionFailed);
+
+        sim.run_for(Duration::from_secs(1));
+        for node in sim.node_ids() {
+            assert_eq!(*sim.node(&node).state(0), 6);
+
+            let output = call(&mut sim, &node, Replicated::Command(Counter::Read));
+            assert!(matches!(output, Ok(CounterOk::ReadOk { value: 6 })));
+        }
+
+        assert!(sim.node("n0").raft(0).log().len() < 7);
+    }
+
+    #[test]
+    fn elections_are_fixed_by_the_seed() {
+        // every leader is crashed in turn, so there are elections to tell runs apart
+        let run = |seed| {
+            let mut sim = Sim::new(3, seed, || Machine::new(0));
+            let mut history = Vec::new();
+            for _ in 0..5 {
+                sim.run_for(Duration::from_secs(3));
+                let statuses = sim
+                    .node_ids()
+                    .iter()
+                    .map(|node| sim.node(node).raft(0).status())
+                    .collect::<Vec<_>>();
+                let leader = statuses.iter().find(|status| status.role == Role::Leader);
+                if let Some(leader) = leader.map(|status| status.id.clone()) {
+                    sim.crash(&leader);
+                    sim.run_for(Duration::from_secs(1));
+                    sim.restart(&leader);
+                }
+                history.push(statuses);
+            }
+            history
+        };
+
+        assert_eq!(run(11), run(11));
+    }
+
+    #[test]
+    fn forwarded_command_fails_when_leader_is_cut_off() {
+        let mut sim = Sim::new(3, 3, || Machine::new(0));
+        sim.run_for(Duration::from_secs(5));
+
+        let nodes = sim.node_ids();
+        let leader = nodes.iter().find(|node| sim.node(node).raft(0).is_leader());
+        let leader = leader.unwrap().clone();
+        let follower = nodes.into_iter().find(|node| *node != leader).unwrap();
+        sim.partition(Partition::Isolate(leader));
+
+        // the follower forwards it to a leader that never answers, and gives up on it once
+        // the others elect a new one, without knowing whether it went in the log
+        let payload = Replicated::Command(Counter::Add { delta: 1 });
+        let reply = sim.call(&follower, payload, Duration::from_secs(10));
+        let Err(RpcError::Remote(error)) = reply else {
+            panic!("unexpected reply {:?}", reply);
+        };
+
+        assert_eq!(error.code, ErrorCode::Timeout);
+        assert!(sim.node(&follower).groups[0].proposals.is_empty());
+    }
+
+    #[test]
+    fn retries_are_applied_once() {
+        let mut sim = Sim::new(3, 5, || Machine::new(0).snapshot_every(2));
+        sim.run_for(Duration::from_secs(5));
+        sim.crash("n2");
+
+        let add = |sim: &mut Sim<Machine>, node: &str, seq, delta| {
+            let payload = Replicated::Session(Session {
+                seq,
+                command: Counter::Add { delta },
+            });
+            let Ok(CounterOk::AddOk { value }) = call(sim, node, payload) else {
+                panic!("expected the add to succeed");
+            };
+            value
+        };
+
+        assert_eq!(add(&mut sim, "n0", 1, 5), 5);
+        assert_eq!(add(&mut sim, "n1", 1, 5), 5);
+        assert_eq!(add(&mut sim, "n1", 2, 1), 6);
+        assert_eq!(add(&mut sim, "n0", 2, 1), 6);
+        assert_eq!(add(&mut sim, "n0", 3, 1), 7);
+        assert_eq!(add(&mut sim, "n1", 3, 1), 7);
+
+        // n2 comes back empty, and only learns of the session from a snapshot
+        sim.restart("n2");
+        sim.run_for(Duration::from_secs(5));
+
+        assert_eq!(*sim.node("n2").state(0), 7);
+        assert!(matches!(
+            sim.node("n2").groups[0].sessions.clients.get("c0"),
+            Some((3, Ok(_), _))
+        ));
+        assert_eq!(add(&mut sim, "n2", 3, 1), 7);
+    }
+
+    #[test]
+    fn expired_sessions_are_not_applied_again() {
+        let nodes = vec!["n0".to_string()];
+        let raft = Raft::new("n0".to_string(), nodes, RaftConfig::default());
+        let mut group = Group::<i64, Counter>::new(0, 0, 2, raft);
+        let mut add = |client: &str, seq| {
+            let session = Some((client.to_string(), seq));
+            group.execute(session, &Counter::Add { delta: 1 })
+        };
+
+        add("c1", 1).unwrap();
+        add("c2", 1).unwrap();
+        add("c2", 2).unwrap();
+        add("c1", 2).unwrap();
+
+        // c2 was used least recently, so it makes room for c3
+        add("c3", 1).unwrap();
+        assert!(matches!(add("c1", 2), Ok(CounterOk::AddOk { value: 4 })));
+
+        // c2 may have been retrying its last command, which can't be told apart from a new one
+        let error = add("c2", 2).unwrap_err();
+        assert_eq!(error.code, ErrorCode::PreconditionFailed);
+        assert!(matches!(add("c3", 2), Ok(CounterOk::AddOk { value: 6 })));
+
+        // but a new command carries on with the session
+        assert!(matches!(add("c2", 3), Ok(CounterOk::AddOk { value: 7 })));
+
+        // a command the client already moved on from gets an answer too
+        let error = add("c1", 1).unwrap_err();
+        assert_eq!(error.code, ErrorCode::PreconditionFailed);
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "snake_case")]
+    enum Tally {
+        Incr { key: String },
+    }
+
+    #[derive(Clone, Debug, Serialize, Deserialize)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "snake_case")]
+    enum TallyOk {
+        IncrOk { value: u64 },
+    }
+
+    impl Command<BTreeMap<String, u64>> for Tally {
+        type Output = TallyOk;
+
+        fn apply(&self, state: &mut BTreeMap<String, u64>) -> Result<TallyOk, Error> {
+            let Tally::Incr { key } = self;
+            let value = state.entry(key.clone()).or_default();
+            *value += 1;
+            Ok(TallyOk::IncrOk { value: *value })
+        }
+    }
+
+    impl Keyed for Tally {
+        type Key = String;
+
+        fn key(&self) -> &String {
+            let Tally::Incr { key } = self;
+            key
+        }
+    }
+
+    #[test]
+    fn sessions_span_groups() {
+        type Sharded = ReplicatedStateMachine<BTreeMap<String, u64>, Tally>;
+        let mut sim = Sim::new(3, 9, || {
+            Sharded::new(BTreeMap::new()).shard(Sharding::Hash(3))
+        });
+        sim.run_for(Duration::from_secs(5));
+
+        // the client numbers its commands across groups, so each group sees gaps in the session
+        let keys = ["a", "b", "c", "d", "e", "f"];
+        for (i, key) in keys.into_iter().cycle().take(12).enumerate() {
+            let command = Tally::Incr {
+                key: key.to_string(),
+            };
+            let payload = Replicated::Session(Session {
+                seq: i as u64 + 1,
+                command,
+            });
+
+            // and a retry still gets the first attempt's output
+            let expected = i as u64 / keys.len() as u64 + 1;
+            for _ in 0..2 {
+                let output = call(&mut sim, "n1", payload.clone());
+                assert!(matches!(output, Ok(TallyOk::IncrOk { value }) if value == expected));
+            }
+        }
+    }
+
+    #[test]
+    fn groups_split_keys_and_leadership() {
+        type Sharded = ReplicatedStateMachine<BTreeMap<String, u64>, Tally>;
+        let mut sim = Sim::new(3, 7, || {
+            Sharded::new(BTreeMap::new()).shard(Sharding::Hash(3))
+        });
+        sim.run_for(Duration::from_secs(5));
+
+        // each group settles on its preferred leader
+        for (group, node) in ["n0", "n1", "n2"].into_iter().enumerate() {
+            assert!(sim.node(node).raft(group).is_leader());
+        }
+
+        let keys = ["a", "b", "c", "d", "e", "f"];
+        for (i, node) in ["n0", "n1", "n2"].into_iter().cycle().take(12).enumerate() {
+            let key = keys[i % keys.len()].to_string();
+            let output = call(&mut sim, node, Replicated::Command(Tally::Incr { key }));
+            let expected = i as u64 / keys.len() as u64 + 1;
+            assert!(matches!(output, Ok(TallyOk::IncrOk { value }) if value == expected));
+        }
+
+        sim.run_for(Duration::from_secs(1));
+        for node in sim.node_ids() {
+            let node = sim.node(&node);
+            assert_eq!(node.groups(), 3);
+
+            // every key lives in exactly one group, the one it hashes to
+            for key in keys.map(String::from) {
+                let group = Sharding::Hash(3).group(&key);
+                for other in 0..3 {
+                    let value = node.state(other).get(&key).copied();
+                    assert_eq!(value, (other == group).then_some(2));
+                }
+            }
+        }
+    }
+}
@@ -1,20 +1,55 @@
+mod command;
+mod config;
+mod machine;
 mod rpc;
+mod shard;
 mod state;
+mod storage;
 mod timer;
+mod wal;
 
-use std::fmt::Debug;
+#[cfg(test)]
+mod tests;
 
+use std::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    fmt::Debug,
+    rc::Rc,
+    time::Duration,
+};
+
+use serde::Serialize;
+use tracing::info;
+
+use crate::{Clock, SystemClock};
+
+pub use command::{Command, Keyed};
+pub use config::RaftConfig;
+pub use machine::{Envelope, Peer, Replicated, ReplicatedStateMachine, Request, Session, Tick};
 pub use rpc::*;
+pub use shard::Sharding;
 use state::*;
+pub use state::{Config, Entry, Log, Role};
+pub use storage::{HardState, MemoryStorage, Saved, Snapshot, Storage};
 use timer::*;
+pub use wal::{FileStorage, Fsync};
 
 pub struct Raft<C> {
     topology: Topology,
     persistent: PersistentState<C>,
     transient: TransientState,
     timer: Timer,
+    settings: RaftConfig,
+    lease: Option<Duration>,
+    pre_vote: bool,
+    check_quorum: bool,
+    // the role, term and leader last reported, and who to report changes to
+    observed: (Role, u32, Option<String>),
+    on_change: Option<OnChange>,
 }
 
+type OnChange = Box<dyn FnMut(&Status)>;
+
 #[derive(Clone, Debug)]
 pub enum Delivery<C> {
     Unicast(String, Rpc<C>),
@@ -22,31 +57,179 @@ pub enum Delivery<C> {
     Multicast(Vec<(String, Rpc<C>)>),
 }
 
+/// What the state machine should do next, in log order.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Committed<C> {
+    Command(C),
+    /// Replace the state machine with a snapshot of it.
+    Snapshot(Vec<u8>),
+    /// The state machine reflects every write that completed before the read was asked for,
+    /// so it can be served now.
+    Read(u64),
+    /// Leadership changed while the read was waiting, so it may have missed writes.
+    ReadFailed(u64),
+    /// The proposal committed, as the command returned just before.
+    Applied(u64),
+    /// The proposal will never commit, as an entry from another leader committed in its place,
+    /// or no leader took it.
+    Lost(u64),
+    /// This node stopped tracking the proposal without learning whether it committed, as the
+    /// term changed before the leader said where it went, or a snapshot covered its entry.
+    Unknown(u64),
+}
+
+/// What `apply` did with a command.
+#[derive(Clone, Debug)]
+pub enum Proposal<C> {
+    /// Appended to the log as entry `index`, in `term`.
+    Accepted {
+        index: usize,
+        term: u32,
+        delivery: Delivery<C>,
+    },
+    /// Sent to the leader, which tells this node where it appended it.
+    Forwarded(Delivery<C>),
+    Rejected(Rejection),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    NoLeader,
+    /// The leader is handing over to another node.
+    Transferring,
+}
+
+/// What a node knows of the cluster, for debugging.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Status {
+    pub id: String,
+    pub role: Role,
+    pub term: u32,
+    pub leader: Option<String>,
+    /// How many entries are known to be committed.
+    pub commit_len: usize,
+    /// How many of those `consume` returned, or a snapshot covered.
+    pub applied_len: usize,
+    /// How far replication to each follower got, if this node leads.
+    pub progress: BTreeMap<String, Progress>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// Where the next append to the follower starts.
+    pub sent_len: usize,
+    /// How much of the follower's log is known to match the leader's.
+    pub acked_len: usize,
+}
+
+impl<C> Proposal<C> {
+    pub fn delivery(self) -> Option<Delivery<C>> {
+        match self {
+            Proposal::Accepted { delivery, .. } | Proposal::Forwarded(delivery) => Some(delivery),
+            Proposal::Rejected(_) => None,
+        }
+    }
+}
+
 impl<C> Raft<C>
 where
-    C: Clone + Debug,
+    C: Clone + Debug + Serialize + 'static,
 {
-    pub fn new(id: String, nodes: Vec<String>) -> Self {
+    /// Starts out with every one of `nodes` as a voter.
+    pub fn new(id: String, nodes: Vec<String>, settings: RaftConfig) -> Self {
+        let config = Config::new(nodes);
+
         Self {
-            topology: Topology { id, nodes },
-            persistent: PersistentState::default(),
+            topology: Topology {
+                id,
+                initial: config.clone(),
+                config,
+                config_len: 0,
+            },
+            persistent: PersistentState::recover(Box::new(MemoryStorage::new())),
             transient: TransientState::default(),
-            timer: Timer::new(1000, 1000),
+            timer: Timer::new(
+                Rc::new(SystemClock::new()),
+                settings.election_timeout.clone(),
+            ),
+            settings,
+            lease: None,
+            pre_vote: false,
+            check_quorum: false,
+            observed: (Role::Follower, 0, None),
+            on_change: None,
         }
     }
 
+    /// Keeps term, vote and log in `storage`, resuming from whatever it already holds.
+    pub fn storage(mut self, storage: impl Storage<C> + 'static) -> Self {
+        self.persistent = PersistentState::recover(Box::new(storage));
+        self.observed.1 = self.persistent.term;
+        self.reconfigured();
+        self
+    }
+
+    /// Drives election timeouts off `clock` instead of the system clock.
+    pub fn clock(mut self, clock: Rc<dyn Clock>) -> Self {
+        self.timer = self.timer.clock(clock);
+        self
+    }
+
+    /// Draws election timeouts from an rng seeded with `seed`, so a run under a virtual clock
+    /// can be replayed.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.timer = self.timer.seed(seed);
+        self
+    }
+
+    /// Lets the leader serve reads without a round of heartbeats for `lease` after a quorum
+    /// acknowledged one. In exchange, nodes that heard from a leader within the election
+    /// timeout refuse to vote for anyone else, so `lease` must be shorter than the timeout by
+    /// more than the clocks can drift apart.
+    pub fn lease(mut self, lease: Duration) -> Self {
+        self.lease = Some(lease);
+        self
+    }
+
+    /// Runs a round of non-binding votes before starting an election, which only goes ahead
+    /// if it could be won. A node cut off from the cluster then can't bump its term and
+    /// depose the leader once it's back.
+    pub fn pre_vote(mut self, enabled: bool) -> Self {
+        self.pre_vote = enabled;
+        self
+    }
+
+    /// Makes the leader step down if it doesn't hear from a quorum within an election
+    /// timeout, so clients of a leader cut off from the cluster can try another node.
+    pub fn check_quorum(mut self, enabled: bool) -> Self {
+        self.check_quorum = enabled;
+        self
+    }
+
+    /// Calls `callback` whenever the role, term or leader changes, which the node also traces.
+    pub fn on_change(mut self, callback: impl FnMut(&Status) + 'static) -> Self {
+        self.on_change = Some(Box::new(callback));
+        self
+    }
+
     pub fn id(&self) -> &String {
         &self.topology.id
     }
 
+    /// Every member of the current configuration.
     pub fn nodes(&self) -> impl Iterator<Item = &String> {
-        self.topology.nodes.iter()
+        self.topology.config.nodes()
     }
 
     pub fn others(&self) -> impl Iterator<Item = &String> {
-        self.topology.nodes.iter().filter(|node| *node != self.id())
+        self.nodes().filter(|node| *node != self.id())
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.topology.config
     }
 
+    /// The entries that were not compacted into a snapshot yet.
     pub fn log(&self) -> &Vec<Log<C>> {
         &self.persistent.log
     }
@@ -55,64 +238,358 @@ where
         self.transient.role == Role::Leader
     }
 
-    pub fn apply(&mut self, command: C) -> Option<Delivery<C>> {
-        let term = self.persistent.term;
+    pub fn term(&self) -> u32 {
+        self.persistent.term
+    }
 
+    pub fn status(&self) -> Status {
+        let progress = if self.is_leader() {
+            self.others()
+                .map(|node| {
+                    let progress = Progress {
+                        sent_len: self.transient.sent_len.get(node).copied().unwrap_or(0),
+                        acked_len: self.transient.acked_len.get(node).copied().unwrap_or(0),
+                    };
+                    (node.clone(), progress)
+                })
+                .collect()
+        } else {
+            BTreeMap::new()
+        };
+
+        Status {
+            id: self.id().clone(),
+            role: self.transient.role,
+            term: self.persistent.term,
+            leader: self.transient.leader.clone(),
+            commit_len: self.persistent.commit_len,
+            applied_len: self.transient.consumed,
+            progress,
+        }
+    }
+
+    /// Proposes `command` for the log. Once its entry commits, `consume` returns the command
+    /// followed by `Committed::Applied(id)`, or `Committed::Lost(id)` if another one took its
+    /// place. If this node never learns where it went, or only gets it as part of a snapshot,
+    /// `Committed::Unknown(id)` comes instead, so every proposal gets exactly one of them.
+    pub fn apply(&mut self, id: u64, command: C) -> Proposal<C> {
         if self.is_leader() {
-            self.persistent.log.push(Log { term, command });
-            self.persistent.persist();
+            // the log has to stop growing for the transfer target to catch up
+            if self.transient.transfer.is_some() {
+                return Proposal::Rejected(Rejection::Transferring);
+            }
 
-            self.transient
-                .acked_len
-                .insert(self.id().clone(), self.persistent.log.len());
+            let term = self.persistent.term;
+            let index = self.persistent.len();
+            self.append(Entry::Command(command));
+            self.transient.proposals.push(Proposed {
+                id,
+                term,
+                index: Some(index),
+            });
+
+            return Proposal::Accepted {
+                index,
+                term,
+                delivery: Delivery::Multicast(self.on_replicate(false)),
+            };
+        }
 
-            Some(self.on_replicate())
-        } else {
-            let leader = self.transient.leader.clone()?;
-            if &leader == self.id() {
-                return None;
+        let leader = self.transient.leader.clone();
+        let Some(leader) = leader.filter(|leader| leader != self.id()) else {
+            return Proposal::Rejected(Rejection::NoLeader);
+        };
+
+        self.transient.proposals.push(Proposed {
+            id,
+            term: self.persistent.term,
+            index: None,
+        });
+
+        Proposal::Forwarded(Delivery::Unicast(
+            leader,
+            Rpc {
+                term: self.persistent.term,
+                payload: RpcType::ForwardRequest(ForwardRequest {
+                    follower: self.id().clone(),
+                    id,
+                    command,
+                }),
+            },
+        ))
+    }
+
+    /// Asks to read the state machine linearizably. `consume` returns `Committed::Read(id)`
+    /// once it's safe to serve, or `Committed::ReadFailed(id)` if that's no longer possible.
+    pub fn read(&mut self, id: u64) -> Option<Delivery<C>> {
+        let read = Read {
+            id,
+            origin: self.id().clone(),
+            term: self.persistent.term,
+            round: self.transient.round + 1,
+            index: None,
+        };
+
+        if self.is_leader() {
+            return self.on_read(read);
+        }
+
+        match self.transient.leader.clone() {
+            Some(leader) if leader != *self.id() => {
+                self.transient.reads.push(read);
+
+                Some(Delivery::Unicast(
+                    leader,
+                    Rpc {
+                        term: self.persistent.term,
+                        payload: RpcType::ReadRequest(ReadRequest {
+                            follower: self.id().clone(),
+                            id,
+                        }),
+                    },
+                ))
             }
 
-            Some(Delivery::Unicast(
-                leader.to_string(),
-                Rpc {
-                    term: self.persistent.term,
-                    payload: RpcType::ForwardRequest(ForwardRequest {
-                        follower: self.id().clone(),
-                        command,
-                    }),
-                },
-            ))
+            _ => {
+                self.transient.failed_reads.push_back(id);
+                None
+            }
         }
     }
 
-    pub fn consume(&mut self) -> Option<C> {
-        if self.transient.consumed < self.persistent.commit_len {
+    pub fn consume(&mut self) -> Option<Committed<C>> {
+        if let Some(settled) = self.settled() {
+            return Some(settled);
+        }
+
+        // reads from before a change of leadership may have missed writes of the new leader
+        let term = self.persistent.term;
+        let (stale, reads) = std::mem::take(&mut self.transient.reads)
+            .into_iter()
+            .partition::<Vec<_>, _>(|read| read.term != term);
+        self.transient.reads = reads;
+        self.transient
+            .failed_reads
+            .extend(stale.into_iter().map(|read| read.id));
+
+        // a forwarded proposal the leader didn't answer before the term changed is lost track of
+        let (forgotten, proposals) = std::mem::take(&mut self.transient.proposals)
+            .into_iter()
+            .partition::<Vec<_>, _>(|proposal| proposal.index.is_none() && proposal.term != term);
+        self.transient.proposals = proposals;
+        self.transient
+            .settled
+            .extend(forgotten.into_iter().map(|proposal| (proposal.id, None)));
+
+        if let Some(id) = self.transient.failed_reads.pop_front() {
+            return Some(Committed::ReadFailed(id));
+        }
+
+        let consumed = self.transient.consumed;
+
+        if let Some(snapshot) = self.persistent.snapshot.as_ref() {
+            if snapshot.len > consumed {
+                self.transient.consumed = snapshot.len;
+
+                // whatever entries the snapshot covers, it doesn't say which they were
+                let (covered, proposals) = std::mem::take(&mut self.transient.proposals)
+                    .into_iter()
+                    .partition::<Vec<_>, _>(|proposal| {
+                        proposal.index.is_some_and(|index| index < snapshot.len)
+                    });
+                self.transient.proposals = proposals;
+                self.transient
+                    .settled
+                    .extend(covered.into_iter().map(|proposal| (proposal.id, None)));
+
+                return Some(Committed::Snapshot(snapshot.data.clone()));
+            }
+        }
+
+        let ready = self.transient.reads.iter().position(|read| {
+            read.origin == *self.id() && read.index.is_some_and(|index| index <= consumed)
+        });
+        if let Some(ready) = ready {
+            let read = self.transient.reads.remove(ready);
+            return Some(Committed::Read(read.id));
+        }
+
+        while self.transient.consumed < self.persistent.commit_len {
+            let index = self.transient.consumed;
+            let entry = self.persistent.entry(index)?;
             self.transient.consumed += 1;
-            Some(
-                self.persistent.log[self.transient.consumed - 1]
-                    .command
-                    .clone(),
-            )
-        } else {
-            None
+
+            let term = entry.term;
+            let command = match &entry.entry {
+                Entry::Command(command) => Some(command.clone()),
+                _ => None,
+            };
+
+            self.settle(index, term);
+            if let Some(command) = command {
+                return Some(Committed::Command(command));
+            }
+
+            if let Some(settled) = self.settled() {
+                return Some(settled);
+            }
+        }
+
+        self.settled()
+    }
+
+    /// Moves the cluster over to `voters`, first through a joint configuration of the old
+    /// and new voters, then to the new voters alone once that one commits. Only the leader
+    /// can start a change, and only once the previous one is done.
+    pub fn reconfigure(&mut self, voters: impl IntoIterator<Item = String>) -> Option<Delivery<C>> {
+        let changing = self.topology.config.is_joint()
+            || self.topology.config_len > self.persistent.commit_len;
+
+        if !self.is_leader() || changing || self.transient.transfer.is_some() {
+            return None;
+        }
+
+        let voters = voters.into_iter().collect::<BTreeSet<_>>();
+        let config = Config {
+            voters: self.topology.config.voters.clone(),
+            learners: &self.topology.config.learners - &voters,
+            next: Some(voters),
+        };
+
+        self.append(Entry::Config(config));
+        Some(Delivery::Multicast(self.on_replicate(false)))
+    }
+
+    /// Adds `learner` to the cluster without a vote, so it can catch up on the log without
+    /// changing what a majority is. Only the leader can add one, and only while no other change
+    /// is under way.
+    pub fn add_learner(&mut self, learner: String) -> Option<Delivery<C>> {
+        let changing = self.topology.config.is_joint()
+            || self.topology.config_len > self.persistent.commit_len;
+
+        if !self.is_leader() || changing || self.topology.config.contains(&learner) {
+            return None;
+        }
+
+        let mut config = self.topology.config.clone();
+        config.learners.insert(learner);
+
+        self.append(Entry::Config(config));
+        Some(Delivery::Multicast(self.on_replicate(false)))
+    }
+
+    /// Makes `learner` a voter, through a joint configuration like any other change. Refused
+    /// until its log has caught up with everything committed, as it would only hold up commits
+    /// until then.
+    pub fn promote(&mut self, learner: String) -> Option<Delivery<C>> {
+        let config = &self.topology.config;
+        let acked = self.transient.acked_len.get(&learner).copied().unwrap_or(0);
+
+        if !config.learners.contains(&learner) || acked < self.persistent.commit_len {
+            return None;
+        }
+
+        let mut voters = config.voters.clone();
+        voters.insert(learner);
+        self.reconfigure(voters)
+    }
+
+    /// Hands leadership over to `target`: stops taking proposals, brings its log up to date,
+    /// then has it start an election right away. Proposals are taken again if `target` hasn't
+    /// taken over within an election timeout.
+    pub fn transfer_leadership(&mut self, target: String) -> Option<Delivery<C>> {
+        if !self.is_leader() || target == *self.id() || !self.topology.config.is_voter(&target) {
+            return None;
+        }
+
+        // the target may win an election before the transfer is given up on
+        self.transient.transfer = Some((target, self.timer.now()));
+        self.transient.lease_until = Duration::ZERO;
+
+        match self.timeout_now() {
+            Some((target, rpc)) => Some(Delivery::Unicast(target, rpc)),
+            None => Some(Delivery::Multicast(self.on_replicate(false))),
+        }
+    }
+
+    /// Compacts everything `consume` returned so far into `data`, a snapshot of the state
+    /// machine at this point.
+    pub fn snapshot(&mut self, data: Vec<u8>) {
+        if self.transient.consumed > self.persistent.offset() {
+            self.persistent.compact(self.transient.consumed, data);
         }
     }
 
     pub fn tick(&mut self) -> Option<Delivery<C>> {
+        let delivery = self.on_tick();
+        self.observe();
+        delivery
+    }
+
+    pub fn process(&mut self, from: String, rpc: Rpc<C>) -> Option<Delivery<C>> {
+        let delivery = self.on_rpc(from, rpc);
+        self.observe();
+        delivery
+    }
+
+    // reports a change of role, term or leader since the last one, as elections are easiest
+    // to follow from those
+    fn observe(&mut self) {
+        let role = self.transient.role;
+        let term = self.persistent.term;
+        let leader = &self.transient.leader;
+        if self.observed == (role, term, leader.clone()) {
+            return;
+        }
+
+        info!(node = %self.topology.id, ?role, term, ?leader, "raft state changed");
+        self.observed = (role, term, leader.clone());
+
+        if let Some(mut callback) = self.on_change.take() {
+            callback(&self.status());
+            self.on_change = Some(callback);
+        }
+    }
+
+    fn on_tick(&mut self) -> Option<Delivery<C>> {
         if self.is_leader() {
-            return Some(self.on_replicate());
+            // checking the quorum restarted the timer already
+            if self.check_quorum && self.timer.expired() && !self.heard_from_quorum() {
+                self.step_down();
+                return None;
+            }
+
+            let transferring = self.transient.transfer.as_ref();
+            if transferring
+                .is_some_and(|(_, started)| self.timer.now() - *started > self.timer.min())
+            {
+                self.transient.transfer = None;
+                self.transient.lease_round = self.transient.round + 1;
+            }
+
+            let since = self.timer.now() - self.transient.last_heartbeat;
+            if since < self.settings.heartbeat_interval {
+                return None;
+            }
+
+            return Some(Delivery::Multicast(self.on_replicate(true)));
         }
 
-        if self.timer.expired() {
-            let message = self.on_timeout();
+        // learners and nodes removed from the cluster don't take part in elections
+        if self.timer.expired() && self.topology.config.is_voter(self.id()) {
+            let message = if self.pre_vote {
+                self.on_pre_vote_timeout()
+            } else {
+                self.on_timeout(false)
+            };
+
             return Some(Delivery::Broadcast(message));
         }
 
         None
     }
 
-    pub fn process(&mut self, from: String, rpc: Rpc<C>) -> Option<Delivery<C>> {
+    fn on_rpc(&mut self, from: String, rpc: Rpc<C>) -> Option<Delivery<C>> {
         match rpc.payload {
             RpcType::VoteRequest(request) => {
                 let message = self.on_vote_request(rpc.term, request);
@@ -124,65 +601,483 @@ where
                 None
             }
 
+            RpcType::PreVoteRequest(request) => {
+                let message = self.on_pre_vote_request(rpc.term, request);
+                Some(Delivery::Unicast(from, message))
+            }
+
+            RpcType::PreVoteResponse(response) => {
+                let message = self.on_pre_vote_response(rpc.term, response)?;
+                Some(Delivery::Broadcast(message))
+            }
+
             RpcType::AppendRequest(request) => {
                 let message = self.on_append_request(rpc.term, request);
                 Some(Delivery::Unicast(from, message))
             }
 
-            RpcType::AppendResponse(response) => {
-                self.on_append_response(rpc.term, response);
+            RpcType::AppendResponse(response) => self.on_append_response(rpc.term, response),
+
+            RpcType::InstallSnapshot(request) => {
+                let message = self.on_install_snapshot(rpc.term, request);
+                Some(Delivery::Unicast(from, message))
+            }
+
+            RpcType::SnapshotResponse(response) => {
+                let message = self.on_snapshot_response(rpc.term, response)?;
+                Some(Delivery::Unicast(from, message))
+            }
+
+            RpcType::ForwardRequest(request) => self.on_forward_request(request),
+
+            RpcType::ForwardResponse(response) => {
+                self.on_forward_response(rpc.term, response);
+                None
+            }
+
+            RpcType::TimeoutNow(_) => {
+                let current = rpc.term == self.persistent.term && !self.is_leader();
+                if !current || !self.topology.config.is_voter(self.id()) {
+                    return None;
+                }
+
+                Some(Delivery::Broadcast(self.on_timeout(true)))
+            }
+
+            RpcType::ReadRequest(request) => {
+                if rpc.term != self.persistent.term || !self.is_leader() {
+                    return None;
+                }
+
+                self.on_read(Read {
+                    id: request.id,
+                    origin: request.follower,
+                    term: rpc.term,
+                    round: self.transient.round + 1,
+                    index: None,
+                })
+            }
+
+            RpcType::ReadResponse(response) => {
+                let term = self.persistent.term;
+                let read = self.transient.reads.iter_mut().find(|read| {
+                    read.id == response.id && read.term == term && read.index.is_none()
+                });
+
+                if let Some(read) = read.filter(|_| rpc.term == term) {
+                    read.index = Some(response.index);
+                }
+
                 None
             }
+        }
+    }
+
+    fn append(&mut self, entry: Entry<C>) {
+        let config = matches!(entry, Entry::Config(_));
+
+        let term = self.persistent.term;
+        self.persistent.push(Log { term, entry });
+        self.persistent.persist();
+
+        self.transient
+            .acked_len
+            .insert(self.id().clone(), self.persistent.len());
 
-            RpcType::ForwardRequest(request) => self.apply(request.command),
+        if config {
+            self.reconfigured();
         }
+
+        self.commit_commands();
     }
 
-    fn on_replicate(&self) -> Delivery<C> {
-        let mut messages = Vec::new();
+    // picks up the latest configuration after the log changed
+    fn reconfigured(&mut self) {
+        let (len, config) = self
+            .persistent
+            .config(self.persistent.len())
+            .unwrap_or((0, self.topology.initial.clone()));
 
-        for node in self.topology.nodes.iter() {
-            if node == self.id() {
-                continue;
-            }
+        self.topology.config = config;
+        self.topology.config_len = len;
+    }
 
-            let prefix_len = self.transient.sent_len.get(node).copied().unwrap_or(0);
-            let suffix = self.persistent.log[prefix_len..].to_vec();
+    // decides the proposals at `index`, now that the entry there of `term` is committed
+    fn settle(&mut self, index: usize, term: u32) {
+        let (settled, proposals) = std::mem::take(&mut self.transient.proposals)
+            .into_iter()
+            .partition::<Vec<_>, _>(|proposal| proposal.index == Some(index));
+        self.transient.proposals = proposals;
+
+        self.transient.settled.extend(
+            settled
+                .into_iter()
+                .map(|proposal| (proposal.id, Some(proposal.term == term))),
+        );
+    }
 
-            let prefix_term = if prefix_len > 0 {
-                self.persistent.log[prefix_len - 1].term
-            } else {
-                0
-            };
+    fn settled(&mut self) -> Option<Committed<C>> {
+        let (id, applied) = self.transient.settled.pop_front()?;
+        Some(match applied {
+            Some(true) => Committed::Applied(id),
+            Some(false) => Committed::Lost(id),
+            None => Committed::Unknown(id),
+        })
+    }
+
+    fn on_forward_request(&mut self, request: ForwardRequest<C>) -> Option<Delivery<C>> {
+        let follower = request.follower.clone();
+        let id = request.id;
+
+        if self.is_leader() && self.transient.transfer.is_none() {
+            let index = self.persistent.len();
+            self.append(Entry::Command(request.command));
 
+            let mut messages = self.on_replicate(false);
             messages.push((
-                node.clone(),
+                follower,
                 Rpc {
                     term: self.persistent.term,
-                    payload: RpcType::AppendRequest(AppendRequest {
-                        leader: self.id().clone(),
-                        prefix_len,
-                        prefix_term,
-                        commit_len: self.persistent.commit_len,
-                        suffix,
+                    payload: RpcType::ForwardResponse(ForwardResponse {
+                        id,
+                        index: Some(index),
                     }),
                 },
             ));
+
+            return Some(Delivery::Multicast(messages));
         }
 
-        Delivery::Multicast(messages)
+        // passed on to whoever this node thinks leads now
+        let leader = self.transient.leader.clone();
+        if let Some(leader) = leader.filter(|leader| leader != self.id() && *leader != follower) {
+            let rpc = Rpc {
+                term: self.persistent.term,
+                payload: RpcType::ForwardRequest(request),
+            };
+
+            return Some(Delivery::Unicast(leader, rpc));
+        }
+
+        Some(Delivery::Unicast(
+            follower,
+            Rpc {
+                term: self.persistent.term,
+                payload: RpcType::ForwardResponse(ForwardResponse { id, index: None }),
+            },
+        ))
+    }
+
+    fn on_forward_response(&mut self, term: u32, response: ForwardResponse) {
+        let position = self
+            .transient
+            .proposals
+            .iter()
+            .position(|proposal| proposal.id == response.id && proposal.index.is_none());
+        let Some(position) = position else {
+            return;
+        };
+
+        let Some(index) = response.index else {
+            self.transient.proposals.remove(position);
+            self.transient.settled.push_back((response.id, Some(false)));
+            return;
+        };
+
+        let proposal = &mut self.transient.proposals[position];
+        proposal.index = Some(index);
+        proposal.term = term;
+
+        // the entry may have been consumed before the answer came
+        if index < self.transient.consumed {
+            match self.persistent.entry(index).map(|entry| entry.term) {
+                Some(term) => self.settle(index, term),
+                None => {
+                    self.transient.proposals.remove(position);
+                    self.transient.settled.push_back((response.id, None));
+                }
+            }
+        }
     }
 
-    fn on_timeout(&mut self) -> Rpc<C> {
+    fn on_read(&mut self, mut read: Read) -> Option<Delivery<C>> {
+        // within the lease nobody else can have been elected, so there's no need to check
+        if !self.leased() {
+            self.transient.reads.push(read);
+            return Some(Delivery::Multicast(self.on_replicate(true)));
+        }
+
+        let index = self.persistent.commit_len;
+        if read.origin == *self.id() {
+            read.index = Some(index);
+            self.transient.reads.push(read);
+            return None;
+        }
+
+        Some(Delivery::Unicast(
+            read.origin,
+            Rpc {
+                term: self.persistent.term,
+                payload: RpcType::ReadResponse(ReadResponse { id: read.id, index }),
+            },
+        ))
+    }
+
+    // a leader only knows everything that was committed once it commits an entry of its own
+    fn knows_commit(&self) -> bool {
+        self.persistent.term(self.persistent.commit_len) == Some(self.persistent.term)
+    }
+
+    // voters don't hold on to a leader that is handing over, so neither can its lease
+    fn leased(&self) -> bool {
+        self.lease.is_some()
+            && self.transient.transfer.is_none()
+            && self.knows_commit()
+            && self.timer.now() < self.transient.lease_until
+    }
+
+    // tells the transfer target to take over, once it has the whole log
+    fn timeout_now(&self) -> Option<(String, Rpc<C>)> {
+        let (target, _) = self.transient.transfer.as_ref()?;
+
+        let acked = self.transient.acked_len.get(target).copied().unwrap_or(0);
+        if acked < self.persistent.len() {
+            return None;
+        }
+
+        let rpc = Rpc {
+            term: self.persistent.term,
+            payload: RpcType::TimeoutNow(TimeoutNow {
+                leader: self.id().clone(),
+            }),
+        };
+
+        Some((target.clone(), rpc))
+    }
+
+    // the latest heartbeat round a quorum acknowledged
+    fn confirmed_round(&self) -> u64 {
+        let heard = |node: &String| {
+            if node == self.id() {
+                self.transient.round
+            } else {
+                self.transient.heard.get(node).copied().unwrap_or(0)
+            }
+        };
+
+        let mut rounds = self.topology.config.nodes().map(heard).collect::<Vec<_>>();
+        rounds.sort_unstable();
+
+        rounds
+            .into_iter()
+            .rev()
+            .find(|round| self.topology.config.quorum(|node| heard(node) >= *round))
+            .unwrap_or(0)
+    }
+
+    // sets the read index of the reads the leader confirmed it still leads for
+    fn confirm_reads(&mut self) -> Vec<(String, Rpc<C>)> {
+        if !self.is_leader() || !self.knows_commit() {
+            return Vec::new();
+        }
+
+        let confirmed = self.confirmed_round();
+        while let Some((round, sent)) = self.transient.round_sent.front().copied() {
+            if round > confirmed {
+                break;
+            }
+
+            let leases = self.transient.transfer.is_none() && round >= self.transient.lease_round;
+            if let (Some(lease), true) = (self.lease, round == confirmed && leases) {
+                self.transient.lease_until = self.transient.lease_until.max(sent + lease);
+            }
+
+            self.transient.round_sent.pop_front();
+        }
+
+        let index = self.persistent.commit_len;
+        let mut responses = Vec::new();
+
+        for read in self.transient.reads.iter_mut() {
+            if read.index.is_none() && read.round <= confirmed {
+                read.index = Some(index);
+
+                if read.origin != self.topology.id {
+                    let response = ReadResponse { id: read.id, index };
+                    responses.push((
+                        read.origin.clone(),
+                        Rpc {
+                            term: self.persistent.term,
+                            payload: RpcType::ReadResponse(response),
+                        },
+                    ));
+                }
+            }
+        }
+
+        let id = &self.topology.id;
+        self.transient
+            .reads
+            .retain(|read| read.origin == *id || read.index.is_none());
+
+        responses
+    }
+
+    // sends each follower the entries it's missing, as far as the limits allow, or with
+    // `heartbeat` an append to every follower, even with nothing new
+    fn on_replicate(&mut self, heartbeat: bool) -> Vec<(String, Rpc<C>)> {
+        self.transient.round += 1;
+
+        let round = self.transient.round;
+        let now = self.timer.now();
+        self.transient.round_sent.push_back((round, now));
+
+        if heartbeat {
+            self.transient.last_heartbeat = now;
+        }
+
+        let followers = self.others().cloned().collect::<Vec<_>>();
+        followers
+            .into_iter()
+            .flat_map(|node| self.replicate(node, heartbeat))
+            .collect()
+    }
+
+    fn replicate(&mut self, node: String, heartbeat: bool) -> Vec<(String, Rpc<C>)> {
+        let in_flight = self.transient.in_flight.entry(node.clone()).or_default();
+        let max_in_flight = self.settings.max_in_flight.max(1);
+
+        // once the oldest append went unanswered for a whole heartbeat, it was likely lost, and
+        // the ones after it are rejected for lack of it, so they're all sent again
+        let now = self.timer.now();
+        let lost = in_flight
+            .front()
+            .is_some_and(|(_, sent)| now - *sent >= self.settings.heartbeat_interval);
+
+        if heartbeat && in_flight.len() >= max_in_flight && lost {
+            in_flight.clear();
+            let acked = self.transient.acked_len.get(&node).copied().unwrap_or(0);
+            self.transient.sent_len.insert(node.clone(), acked);
+        }
+
+        let prefix_len = self.transient.sent_len.get(&node).copied().unwrap_or(0);
+        if prefix_len < self.persistent.offset() {
+            if !heartbeat {
+                return Vec::new();
+            }
+
+            let sent = self.transient.snapshot_sent.get(&node).copied();
+            return vec![(node, self.snapshot_chunk(sent.unwrap_or(0)))];
+        }
+
+        // until the follower acknowledges something, it's unknown where its log matches, so
+        // it's probed with one append per heartbeat
+        if !self.transient.matched.contains(&node) {
+            if !heartbeat {
+                return Vec::new();
+            }
+
+            let suffix = self.batch(prefix_len);
+            return vec![(node, self.append_request(prefix_len, suffix))];
+        }
+
+        let mut messages = Vec::new();
+        loop {
+            let prefix_len = self.transient.sent_len.get(&node).copied().unwrap_or(0);
+            let in_flight = self.transient.in_flight.get(&node).map_or(0, VecDeque::len);
+
+            let pending = prefix_len < self.persistent.len();
+            if !pending || in_flight >= max_in_flight {
+                if heartbeat && messages.is_empty() {
+                    messages.push((node.clone(), self.append_request(prefix_len, Vec::new())));
+                }
+
+                return messages;
+            }
+
+            let suffix = self.batch(prefix_len);
+            let len = prefix_len + suffix.len();
+            self.transient
+                .in_flight
+                .entry(node.clone())
+                .or_default()
+                .push_back((len, now));
+            self.transient.sent_len.insert(node.clone(), len);
+
+            messages.push((node.clone(), self.append_request(prefix_len, suffix)));
+        }
+    }
+
+    // the entries from `from` on that fit in one append, at least one if there are any
+    fn batch(&self, from: usize) -> Vec<Log<C>> {
+        let mut bytes = 0;
+
+        self.persistent
+            .entries(from)
+            .iter()
+            .take(self.settings.max_entries.max(1))
+            .enumerate()
+            .take_while(|(i, log)| {
+                bytes += serde_json::to_vec(log).map_or(0, |json| json.len());
+                *i == 0 || bytes <= self.settings.max_bytes
+            })
+            .map(|(_, log)| log.clone())
+            .collect()
+    }
+
+    fn append_request(&self, prefix_len: usize, suffix: Vec<Log<C>>) -> Rpc<C> {
+        Rpc {
+            term: self.persistent.term,
+            payload: RpcType::AppendRequest(AppendRequest {
+                leader: self.id().clone(),
+                prefix_len,
+                prefix_term: self.persistent.term(prefix_len).unwrap_or(0),
+                commit_len: self.persistent.commit_len,
+                suffix,
+                round: self.transient.round,
+            }),
+        }
+    }
+
+    fn snapshot_chunk(&self, offset: usize) -> Rpc<C> {
+        let snapshot = self
+            .persistent
+            .snapshot
+            .as_ref()
+            .expect("No snapshot to send");
+
+        let offset = offset.min(snapshot.data.len());
+        let end = snapshot
+            .data
+            .len()
+            .min(offset + self.settings.max_bytes.max(1));
+
+        Rpc {
+            term: self.persistent.term,
+            payload: RpcType::InstallSnapshot(InstallSnapshot {
+                leader: self.id().clone(),
+                len: snapshot.len,
+                last_term: snapshot.term,
+                config: snapshot.config.clone(),
+                offset,
+                data: snapshot.data[offset..end].to_vec(),
+                done: end == snapshot.data.len(),
+            }),
+        }
+    }
+
+    fn on_timeout(&mut self, transfer: bool) -> Rpc<C> {
         self.persistent.term += 1;
         self.persistent.voted_for = Some(self.id().clone());
 
         self.persistent.persist();
 
         self.transient.role = Role::Candidate;
+        self.transient.leader = None;
+        self.transient.votes_received.clear();
         self.transient.votes_received.insert(self.id().clone());
 
-        let last_log_index = self.persistent.log.len();
+        let last_log_index = self.persistent.len();
         let last_log_term = self.persistent.last_log_term().unwrap_or(0);
 
         let message = Rpc {
@@ -191,6 +1086,7 @@ where
                 candidate: self.id().clone(),
                 last_log_index,
                 last_log_term,
+                transfer,
             }),
         };
 
@@ -200,20 +1096,32 @@ where
     }
 
     fn on_vote_request(&mut self, term: u32, request: VoteRequest) -> Rpc<C> {
+        // a leader serving reads off its lease counts on this node not electing anyone else
+        // until the election timeout passes without hearing from it
+        let leader_alive =
+            self.lease.is_some() && self.transient.leader.is_some() && !self.timer.expired();
+
+        if leader_alive && !request.transfer {
+            return Rpc {
+                term: self.persistent.term,
+                payload: RpcType::VoteResponse(VoteResponse {
+                    voter: self.id().clone(),
+                    granted: false,
+                }),
+            };
+        }
+
         if term > self.persistent.term {
             self.persistent.term = term;
             self.persistent.voted_for = None;
 
-            self.transient.role = Role::Follower;
+            // unless the vote is granted, the timer keeps running, so a candidate that can't win
+            // doesn't hold off the elections of those that could
+            self.step_down();
         }
 
-        let last_index = self.persistent.log.len();
-        let last_term = self.persistent.last_log_term().unwrap_or(0);
-
         let term_ok = term == self.persistent.term;
-
-        let log_ok = request.last_log_term > last_term
-            || (request.last_log_term == last_term && request.last_log_index >= last_index);
+        let log_ok = self.log_up_to_date(&request);
 
         let vote_ok = self.persistent.voted_for.is_none()
             || self
@@ -224,6 +1132,9 @@ where
 
         let granted = if term_ok && log_ok && vote_ok {
             self.persistent.voted_for = Some(request.candidate);
+            // the candidate may well win, so give it a full timeout to send its first append
+            // before standing for election against it
+            self.timer.reset();
             true
         } else {
             false
@@ -240,13 +1151,73 @@ where
         }
     }
 
+    // whether a candidate's log has everything this node's log has
+    fn log_up_to_date(&self, request: &VoteRequest) -> bool {
+        let last_index = self.persistent.len();
+        let last_term = self.persistent.last_log_term().unwrap_or(0);
+
+        request.last_log_term > last_term
+            || (request.last_log_term == last_term && request.last_log_index >= last_index)
+    }
+
+    fn on_pre_vote_timeout(&mut self) -> Rpc<C> {
+        self.transient.role = Role::PreCandidate;
+        self.transient.leader = None;
+        self.transient.votes_received.clear();
+        self.transient.votes_received.insert(self.id().clone());
+
+        self.timer.reset();
+
+        Rpc {
+            term: self.persistent.term + 1,
+            payload: RpcType::PreVoteRequest(VoteRequest {
+                candidate: self.id().clone(),
+                last_log_index: self.persistent.len(),
+                last_log_term: self.persistent.last_log_term().unwrap_or(0),
+                transfer: false,
+            }),
+        }
+    }
+
+    // like a vote, but changes nothing, and is refused while there's a leader to follow
+    fn on_pre_vote_request(&self, term: u32, request: VoteRequest) -> Rpc<C> {
+        let leader_alive =
+            self.is_leader() || (self.transient.leader.is_some() && !self.timer.expired());
+
+        let granted = term > self.persistent.term && !leader_alive && self.log_up_to_date(&request);
+
+        Rpc {
+            term: if granted { term } else { self.persistent.term },
+            payload: RpcType::PreVoteResponse(VoteResponse {
+                voter: self.id().clone(),
+                granted,
+            }),
+        }
+    }
+
+    fn on_pre_vote_response(&mut self, term: u32, response: VoteResponse) -> Option<Rpc<C>> {
+        let current = self.transient.role == Role::PreCandidate && term == self.persistent.term + 1;
+        if !current || !response.granted {
+            return None;
+        }
+
+        self.transient.votes_received.insert(response.voter);
+
+        let votes = &self.transient.votes_received;
+        if self.topology.config.quorum(|node| votes.contains(node)) {
+            Some(self.on_timeout(false))
+        } else {
+            None
+        }
+    }
+
     fn on_vote_response(&mut self, term: u32, response: VoteResponse) {
         if term > self.persistent.term {
             self.persistent.term = term;
             self.persistent.voted_for = None;
             self.persistent.persist();
 
-            self.transient.role = Role::Follower;
+            self.step_down();
             self.timer.reset();
 
             return;
@@ -258,18 +1229,31 @@ where
         {
             self.transient.votes_received.insert(response.voter);
 
-            if self.transient.votes_received.len() >= (self.topology.count() + 1) / 2 {
+            let votes = &self.transient.votes_received;
+            if self.topology.config.quorum(|node| votes.contains(node)) {
                 self.transient.role = Role::Leader;
                 self.transient.leader = Some(self.id().clone());
 
                 self.timer.reset();
 
-                for node in self.topology.nodes.iter() {
+                for node in self.topology.config.nodes() {
                     self.transient
                         .sent_len
-                        .insert(node.clone(), self.persistent.log.len());
+                        .insert(node.clone(), self.persistent.len());
                     self.transient.acked_len.insert(node.clone(), 0);
                 }
+
+                self.transient.matched.clear();
+                self.transient.in_flight.clear();
+                self.transient.last_heartbeat = Duration::ZERO;
+
+                self.transient.heard.clear();
+                self.transient.active.clear();
+                self.transient.lease_until = Duration::ZERO;
+                self.transient.transfer = None;
+
+                // committing an entry of its own tells the leader how far the log is committed
+                self.append(Entry::Noop);
             }
         }
     }
@@ -278,52 +1262,92 @@ where
         if term > self.persistent.term {
             self.persistent.term = term;
             self.persistent.voted_for = None;
+            self.step_down();
         }
 
-        self.timer.reset();
-
         if self.persistent.term == term {
+            self.timer.reset();
             self.transient.role = Role::Follower;
             self.transient.leader = Some(request.leader);
         }
 
         let term_ok = term == self.persistent.term;
 
-        let log_ok = (self.persistent.log.len() >= request.prefix_len)
-            && (request.prefix_len == 0
-                || self.persistent.log[request.prefix_len - 1].term == request.prefix_term);
+        // entries covered by the snapshot are committed, so they match the leader's already
+        let ack_len = request.prefix_len + request.suffix.len();
+        let mut prefix_len = request.prefix_len;
+        let mut prefix_term = request.prefix_term;
+        let mut suffix = request.suffix;
+
+        let compacted = self.persistent.offset().saturating_sub(prefix_len);
+        if compacted > 0 {
+            let skip = compacted.min(suffix.len());
+            prefix_len += skip;
+            prefix_term = self.persistent.term(prefix_len).unwrap_or(prefix_term);
+            suffix.drain(..skip);
+        }
+
+        let log_ok = prefix_len < self.persistent.offset()
+            || self.persistent.term(prefix_len) == Some(prefix_term);
 
         let ack = if term_ok && log_ok {
-            let suffix_len = request.suffix.len();
-            self.append_commands(request.prefix_len, request.commit_len, request.suffix);
-            Some(request.prefix_len + suffix_len)
+            // past the suffix, the log may still hold entries the leader doesn't have
+            let commit_len = request.commit_len.min(ack_len);
+            if prefix_len >= self.persistent.offset() {
+                self.append_commands(prefix_len, commit_len, suffix);
+            }
+            Some(ack_len)
         } else {
             None
         };
 
+        // tells the leader where the logs may match, so it can skip a whole term's entries
+        // at a time instead of going back one by one
+        let (conflict_term, conflict_len) = match self.persistent.term(prefix_len) {
+            _ if ack.is_some() => (None, 0),
+            None => (None, self.persistent.len().min(prefix_len)),
+            Some(term) => (Some(term), self.persistent.term_start(prefix_len)),
+        };
+
         self.persistent.persist();
 
+        // with a newer term, the reply makes a stale leader step down
         Rpc {
-            term,
+            term: self.persistent.term,
             payload: RpcType::AppendResponse(AppendResponse {
                 follower: self.id().clone(),
                 ack,
+                conflict_term,
+                conflict_len,
+                round: request.round,
             }),
         }
     }
 
     fn append_commands(&mut self, prefix: usize, commit: usize, suffix: Vec<Log<C>>) {
-        if suffix.is_empty() && self.persistent.log.len() > prefix {
-            let index = self.persistent.log.len().min(prefix + suffix.len()) - 1;
-            if self.persistent.log[index].term != suffix[index - prefix].term {
-                self.persistent.log.truncate(prefix);
+        let mut reconfigured = false;
+
+        let len = self.persistent.len();
+        if !suffix.is_empty() && len > prefix {
+            let index = len.min(prefix + suffix.len()) - 1;
+            if self.persistent.term(index + 1) != Some(suffix[index - prefix].term) {
+                self.persistent.truncate(prefix);
+                reconfigured = self.topology.config_len > prefix;
             }
         }
 
-        if prefix + suffix.len() > self.persistent.log.len() {
-            let range = self.persistent.log.len() - prefix..suffix.len();
+        let len = self.persistent.len();
+        if prefix + suffix.len() > len {
+            let range = len - prefix..suffix.len();
             let mut suffix = suffix;
-            self.persistent.log.extend(suffix.drain(range));
+            for log in suffix.drain(range) {
+                reconfigured |= matches!(log.entry, Entry::Config(_));
+                self.persistent.push(log);
+            }
+        }
+
+        if reconfigured {
+            self.reconfigured();
         }
 
         if commit > self.persistent.commit_len {
@@ -333,64 +1357,304 @@ where
         self.persistent.persist();
     }
 
-    fn on_append_response(&mut self, term: u32, response: AppendResponse) {
+    fn on_append_response(&mut self, term: u32, response: AppendResponse) -> Option<Delivery<C>> {
         if term > self.persistent.term {
             self.persistent.term = term;
             self.persistent.voted_for = None;
             self.persistent.persist();
 
-            self.transient.role = Role::Follower;
+            self.step_down();
             self.timer.reset();
 
-            return;
+            return None;
         }
 
+        let mut replicated = Vec::new();
         if term == self.persistent.term && self.transient.role == Role::Leader {
+            self.transient.active.insert(response.follower.clone());
+
+            let heard = self
+                .transient
+                .heard
+                .entry(response.follower.clone())
+                .or_default();
+            *heard = response.round.max(*heard);
+
+            let follower = response.follower;
+            let matched = self.transient.matched.contains(&follower);
+            let in_flight = self
+                .transient
+                .in_flight
+                .entry(follower.clone())
+                .or_default();
+
             if let Some(ack) = response.ack {
+                while in_flight.front().is_some_and(|(len, _)| *len <= ack) {
+                    in_flight.pop_front();
+                }
+
                 let entry = self
                     .transient
                     .acked_len
-                    .entry(response.follower.clone())
+                    .entry(follower.clone())
                     .or_default();
 
                 if ack >= *entry {
-                    self.transient
-                        .sent_len
-                        .insert(response.follower.clone(), ack);
-
                     *entry = ack;
 
+                    let sent = self.transient.sent_len.entry(follower.clone()).or_default();
+                    *sent = if matched { ack.max(*sent) } else { ack };
+                    self.transient.matched.insert(follower.clone());
+
                     self.commit_commands();
                 }
+            } else if matched {
+                // an earlier append was lost or overtaken, so resend from where the logs match
+                in_flight.clear();
+                let acked = self.transient.acked_len.get(&follower).copied();
+                self.transient
+                    .sent_len
+                    .insert(follower.clone(), acked.unwrap_or(0));
             } else {
+                let sent = self.transient.sent_len.get(&follower).copied().unwrap_or(0);
+
+                // past the leader's last entry of the conflicting term, if it has any, the logs
+                // can't match, and neither can they past the follower's first one otherwise
+                let hint = response
+                    .conflict_term
+                    .and_then(|term| self.persistent.term_end(term, sent))
+                    .unwrap_or(response.conflict_len);
+
+                let backtracked = hint.min(sent.saturating_sub(1));
                 self.transient
                     .sent_len
-                    .entry(response.follower)
-                    .and_modify(|sent| *sent = sent.checked_sub(1).unwrap_or(0));
+                    .insert(follower.clone(), backtracked);
+
+                // the next probe goes out right away, so finding where the logs match takes a
+                // round trip per step back rather than a heartbeat
+                replicated.extend(self.replicate(follower, true));
+            }
+
+            // acknowledgements may free up room, or commit a config change that appends more
+            let followers = self.others().cloned().collect::<Vec<_>>();
+            for node in followers {
+                replicated.extend(self.replicate(node, false));
             }
         }
+
+        let mut messages = self.confirm_reads();
+        messages.extend(replicated);
+        messages.extend(self.timeout_now());
+
+        (!messages.is_empty()).then_some(Delivery::Multicast(messages))
     }
 
-    fn commit_commands(&mut self) {
-        let mut commit = self.persistent.commit_len;
+    fn on_install_snapshot(&mut self, term: u32, request: InstallSnapshot) -> Rpc<C> {
+        if term > self.persistent.term {
+            self.persistent.term = term;
+            self.persistent.voted_for = None;
+            self.persistent.persist();
+            self.step_down();
+        }
 
-        while commit < self.persistent.log.len() {
-            let mut acks = 0;
+        let mut response = SnapshotResponse {
+            follower: self.id().clone(),
+            len: request.len,
+            received: 0,
+            done: false,
+        };
+
+        if term < self.persistent.term {
+            return Rpc {
+                term: self.persistent.term,
+                payload: RpcType::SnapshotResponse(response),
+            };
+        }
 
-            for node in self.topology.nodes.iter() {
-                if self.transient.acked_len.get(node).copied().unwrap_or(0) > commit {
-                    acks += 1;
+        // only the current leader holds off elections, not one deposed mid-transfer
+        self.timer.reset();
+
+        self.transient.role = Role::Follower;
+        self.transient.leader = Some(request.leader);
+
+        // chunks of a different snapshot than the one being received start over
+        let incoming =
+            self.transient.incoming.take().filter(|incoming| {
+                incoming.len == request.len && incoming.term == request.last_term
+            });
+
+        let mut incoming = match incoming {
+            Some(incoming) => incoming,
+            None if request.offset == 0 => Snapshot {
+                len: request.len,
+                term: request.last_term,
+                config: request.config,
+                data: Vec::new(),
+            },
+            None => {
+                return Rpc {
+                    term: self.persistent.term,
+                    payload: RpcType::SnapshotResponse(response),
                 }
             }
+        };
+
+        if incoming.data.len() == request.offset {
+            incoming.data.extend(request.data);
+
+            if request.done {
+                response.done = true;
+                if incoming.len > self.persistent.commit_len {
+                    self.persistent.install(incoming);
+                    self.reconfigured();
+                }
 
-            if acks >= (self.topology.count() + 1) / 2 {
-                commit += 1;
+                return Rpc {
+                    term: self.persistent.term,
+                    payload: RpcType::SnapshotResponse(response),
+                };
+            }
+        }
+
+        response.received = incoming.data.len();
+        self.transient.incoming = Some(incoming);
+
+        Rpc {
+            term: self.persistent.term,
+            payload: RpcType::SnapshotResponse(response),
+        }
+    }
+
+    fn on_snapshot_response(&mut self, term: u32, response: SnapshotResponse) -> Option<Rpc<C>> {
+        if term > self.persistent.term {
+            self.persistent.term = term;
+            self.persistent.voted_for = None;
+            self.persistent.persist();
+
+            self.step_down();
+            self.timer.reset();
+
+            return None;
+        }
+
+        if term != self.persistent.term || self.transient.role != Role::Leader {
+            return None;
+        }
+
+        self.transient.active.insert(response.follower.clone());
+
+        if response.done {
+            self.transient.snapshot_sent.remove(&response.follower);
+
+            let acked = self
+                .transient
+                .acked_len
+                .entry(response.follower.clone())
+                .or_default();
+            *acked = response.len.max(*acked);
+
+            self.transient
+                .sent_len
+                .insert(response.follower.clone(), *acked);
+            self.transient.in_flight.remove(&response.follower);
+            self.transient.matched.insert(response.follower);
+            self.commit_commands();
+
+            return None;
+        }
+
+        let sent = self.transient.sent_len.get(&response.follower).copied();
+        if sent.unwrap_or(0) >= self.persistent.offset() {
+            return None;
+        }
+
+        // the follower may have been sent an older snapshot
+        let received = if response.len == self.persistent.offset() {
+            response.received
+        } else {
+            0
+        };
+
+        self.transient
+            .snapshot_sent
+            .insert(response.follower, received);
+
+        Some(self.snapshot_chunk(received))
+    }
+
+    fn commit_commands(&mut self) {
+        let mut replicated = self.persistent.commit_len;
+
+        while replicated < self.persistent.len() {
+            let acked = &self.transient.acked_len;
+            if self
+                .topology
+                .config
+                .quorum(|node| acked.get(node).copied().unwrap_or(0) > replicated)
+            {
+                replicated += 1;
             } else {
                 break;
             }
         }
 
+        // entries of earlier terms can still be overwritten by a newer leader that doesn't have
+        // them, until one of the current term is replicated on top of them
+        let mut commit = self.persistent.commit_len;
+        if self.persistent.term(replicated) == Some(self.persistent.term) {
+            commit = commit.max(replicated);
+        }
+
         self.persistent.commit_len = commit;
         self.persistent.persist();
+
+        if self.topology.config_len <= commit {
+            self.on_config_committed();
+        }
+    }
+
+    fn on_config_committed(&mut self) {
+        if !self.is_leader() {
+            return;
+        }
+
+        let config = &self.topology.config;
+        if let Some(next) = config.next.clone() {
+            // the joint configuration is in place, so the new voters can take over
+            let learners = config.learners.clone();
+            self.append(Entry::Config(Config {
+                learners,
+                ..Config::new(next)
+            }));
+        } else if !config.voters.contains(self.id()) {
+            // the leader was removed, and stayed on only until that was committed
+            self.step_down();
+            self.timer.reset();
+        }
+    }
+
+    // checks whether a quorum responded since the last check, and starts the next one
+    fn heard_from_quorum(&mut self) -> bool {
+        let active = std::mem::take(&mut self.transient.active);
+        self.timer.reset();
+
+        let id = self.id();
+        self.topology
+            .config
+            .quorum(|node| node == id || active.contains(node))
+    }
+
+    // stops leading or standing for election, forgetting any leader and transfer, so the reads
+    // waiting on this node can't be served. Whether that holds off this node's own election is
+    // up to the caller.
+    fn step_down(&mut self) {
+        self.transient.role = Role::Follower;
+        self.transient.leader = None;
+        self.transient.transfer = None;
+
+        let reads = std::mem::take(&mut self.transient.reads);
+        self.transient
+            .failed_reads
+            .extend(reads.into_iter().map(|read| read.id));
     }
 }
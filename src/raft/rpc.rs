@@ -1,6 +1,6 @@
 use serde::{Deserialize, Serialize};
 
-use super::Log;
+use super::{Config, Log};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Rpc<C> {
@@ -12,9 +12,18 @@ pub struct Rpc<C> {
 pub enum RpcType<C> {
     VoteRequest(VoteRequest),
     VoteResponse(VoteResponse),
+    /// Asks whether a vote would be granted for the next term, without starting it.
+    PreVoteRequest(VoteRequest),
+    PreVoteResponse(VoteResponse),
     AppendRequest(AppendRequest<C>),
     AppendResponse(AppendResponse),
+    InstallSnapshot(InstallSnapshot),
+    SnapshotResponse(SnapshotResponse),
     ForwardRequest(ForwardRequest<C>),
+    ForwardResponse(ForwardResponse),
+    ReadRequest(ReadRequest),
+    ReadResponse(ReadResponse),
+    TimeoutNow(TimeoutNow),
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -22,6 +31,8 @@ pub struct VoteRequest {
     pub candidate: String,
     pub last_log_index: usize,
     pub last_log_term: u32,
+    /// Sent on the leader's behalf, so voters shouldn't hold on to it.
+    pub transfer: bool,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -37,16 +48,76 @@ pub struct AppendRequest<C> {
     pub prefix_term: u32,
     pub commit_len: usize,
     pub suffix: Vec<Log<C>>,
+    /// The leader's heartbeat round, which confirms it still leads once a quorum echoes it.
+    pub round: u64,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct AppendResponse {
     pub follower: String,
     pub ack: Option<usize>,
+    /// When rejecting, the term of the follower's entry where the leader's prefix ends, if it
+    /// has one there.
+    pub conflict_term: Option<u32>,
+    /// When rejecting, how many entries precede the follower's first one of `conflict_term`,
+    /// or how long its log is if it has no entry there.
+    pub conflict_len: usize,
+    pub round: u64,
+}
+
+/// A chunk of the leader's snapshot, sent to followers that need entries it compacted.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct InstallSnapshot {
+    pub leader: String,
+    pub len: usize,
+    pub last_term: u32,
+    pub config: Option<Config>,
+    /// Where `data` starts within the snapshot.
+    pub offset: usize,
+    pub data: Vec<u8>,
+    pub done: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnapshotResponse {
+    pub follower: String,
+    pub len: usize,
+    /// How much of the snapshot the follower has, so the leader can resume from there.
+    pub received: usize,
+    pub done: bool,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ForwardRequest<C> {
     pub follower: String,
+    pub id: u64,
     pub command: C,
 }
+
+/// Tells a follower where the leader appended the command it forwarded, in the leader's term.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ForwardResponse {
+    pub id: u64,
+    /// `None` if nobody took the command.
+    pub index: Option<usize>,
+}
+
+/// Asks the leader for a read index on behalf of a follower.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReadRequest {
+    pub follower: String,
+    pub id: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReadResponse {
+    pub id: u64,
+    /// How far the follower's state machine has to get before serving the read.
+    pub index: usize,
+}
+
+/// Tells the target of a leadership transfer to start an election right away.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct TimeoutNow {
+    pub leader: String,
+}
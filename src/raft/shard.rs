@@ -0,0 +1,87 @@
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+/// How keys are split among Raft groups.
+#[derive(Clone, Debug)]
+pub enum Sharding<K> {
+    /// Spreads keys evenly over this many groups, by hash.
+    Hash(usize),
+    /// Splits keys at these bounds, which must be strictly increasing: the first group takes
+    /// the keys below the first bound, the next one the keys from there up to the second, and
+    /// so on, with the last group taking everything from the last bound on.
+    Range(Vec<K>),
+}
+
+impl<K> Sharding<K>
+where
+    K: Hash + Ord,
+{
+    /// Panics if there are no groups, or the range bounds aren't strictly increasing, as keys
+    /// would then go to groups that don't exist, or to the wrong ones.
+    pub fn validate(&self) {
+        match self {
+            Sharding::Hash(groups) => assert!(*groups > 0, "sharding needs at least one group"),
+            Sharding::Range(bounds) => assert!(
+                bounds.windows(2).all(|pair| pair[0] < pair[1]),
+                "range bounds must be strictly increasing"
+            ),
+        }
+    }
+
+    pub fn groups(&self) -> usize {
+        match self {
+            Sharding::Hash(groups) => *groups,
+            Sharding::Range(bounds) => bounds.len() + 1,
+        }
+    }
+
+    /// The group that owns `key`.
+    pub fn group(&self, key: &K) -> usize {
+        match self {
+            Sharding::Hash(groups) => {
+                // every node has to agree on it, so the hasher can't be randomly seeded
+                let mut hasher = DefaultHasher::new();
+                key.hash(&mut hasher);
+                (hasher.finish() % *groups as u64) as usize
+            }
+
+            Sharding::Range(bounds) => bounds.partition_point(|bound| bound <= key),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Sharding;
+
+    #[test]
+    fn range() {
+        let sharding = Sharding::Range(vec![10, 20]);
+        assert_eq!(sharding.groups(), 3);
+
+        let groups = [0, 9, 10, 19, 20, 100].map(|key| sharding.group(&key));
+        assert_eq!(groups, [0, 0, 1, 1, 2, 2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "strictly increasing")]
+    fn unordered_bounds_are_rejected() {
+        Sharding::Range(vec![10, 20, 20]).validate();
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one group")]
+    fn no_groups_are_rejected() {
+        Sharding::<u64>::Hash(0).validate();
+    }
+
+    #[test]
+    fn hash() {
+        let sharding = Sharding::Hash(4);
+        let mut counts = [0; 4];
+        for key in 0..1000 {
+            counts[sharding.group(&key)] += 1;
+        }
+
+        assert!(counts.iter().all(|count| *count > 150));
+    }
+}
@@ -1,10 +1,17 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    time::Duration,
+};
 
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone, Debug, PartialEq)]
+use super::{HardState, Snapshot, Storage};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Role {
     Follower,
+    /// Asking whether it could win an election, before starting one.
+    PreCandidate,
     Candidate,
     Leader,
 }
@@ -12,60 +19,346 @@ pub enum Role {
 #[derive(Clone, Debug)]
 pub struct Topology {
     pub id: String,
-    pub nodes: Vec<String>,
+    /// The configuration the cluster started with, in effect until the log says otherwise.
+    pub initial: Config,
+    /// The latest configuration in the log, which is in effect as soon as it's appended.
+    pub config: Config,
+    /// The length of the log up to the entry holding `config`.
+    pub config_len: usize,
+}
+
+/// The members of the cluster. While changing between configurations, decisions need a
+/// majority of both the old voters and the new ones. Learners get the log like everyone else,
+/// but don't vote or count towards any majority.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Config {
+    pub voters: BTreeSet<String>,
+    pub next: Option<BTreeSet<String>>,
+    #[serde(default)]
+    pub learners: BTreeSet<String>,
 }
 
-impl Topology {
-    pub fn count(&self) -> usize {
-        self.nodes.len()
+impl Config {
+    pub fn new(voters: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            voters: voters.into_iter().collect(),
+            next: None,
+            learners: BTreeSet::new(),
+        }
+    }
+
+    pub fn is_joint(&self) -> bool {
+        self.next.is_some()
+    }
+
+    /// Every member of either configuration, learners included.
+    pub fn nodes(&self) -> impl Iterator<Item = &String> {
+        let next = self.next.iter().flatten();
+        self.voters
+            .iter()
+            .chain(next.filter(|node| !self.voters.contains(*node)))
+            .chain(self.learners.iter().filter(|node| !self.is_voter(node)))
+    }
+
+    pub fn contains(&self, node: &String) -> bool {
+        self.is_voter(node) || self.learners.contains(node)
+    }
+
+    /// Whether `node` votes in either configuration.
+    pub fn is_voter(&self, node: &String) -> bool {
+        self.voters.contains(node) || self.next.as_ref().is_some_and(|next| next.contains(node))
+    }
+
+    /// Whether the nodes for which `agrees` holds make up a quorum.
+    pub fn quorum(&self, agrees: impl Fn(&String) -> bool) -> bool {
+        let majority = |voters: &BTreeSet<String>| {
+            voters.iter().filter(|node| agrees(node)).count() > voters.len() / 2
+        };
+
+        majority(&self.voters) && self.next.as_ref().is_none_or(majority)
     }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Entry<C> {
+    Command(C),
+    Config(Config),
+    /// Appended by every new leader, so it commits an entry of its own term.
+    Noop,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Log<C> {
     pub term: u32,
-    pub command: C,
+    pub entry: Entry<C>,
 }
 
-#[derive(Clone, Debug)]
 pub struct PersistentState<C> {
     pub term: u32,
     pub voted_for: Option<String>,
 
     pub commit_len: usize,
+    /// The entries following the snapshot, if any.
     pub log: Vec<Log<C>>,
+    pub snapshot: Option<Snapshot>,
+
+    storage: Box<dyn Storage<C>>,
+    saved: HardState,
+    // how much of the log is in storage unchanged, and how much is in storage at all
+    saved_len: usize,
+    stored_len: usize,
 }
 
-impl<C> Default for PersistentState<C> {
-    fn default() -> Self {
+impl<C> PersistentState<C> {
+    pub fn recover(mut storage: Box<dyn Storage<C>>) -> Self {
+        let saved = storage.load().expect("Failed to recover raft state");
+        let len = saved.offset() + saved.log.len();
+
         Self {
-            term: 0,
-            voted_for: None,
-            commit_len: 0,
-            log: Vec::new(),
+            term: saved.state.term,
+            voted_for: saved.state.voted_for.clone(),
+            commit_len: saved.state.commit_len,
+            log: saved.log,
+            snapshot: saved.snapshot,
+            storage,
+            saved: saved.state,
+            saved_len: len,
+            stored_len: len,
         }
     }
-}
 
-impl<C> PersistentState<C> {
-    pub fn persist(&self) {}
+    /// How many entries the snapshot covers, which are no longer in `log`.
+    pub fn offset(&self) -> usize {
+        self.snapshot.as_ref().map_or(0, |snapshot| snapshot.len)
+    }
+
+    /// The length of the whole log, snapshot included.
+    pub fn len(&self) -> usize {
+        self.offset() + self.log.len()
+    }
+
+    /// The term of the last entry in the first `len`, or `None` if it was compacted or
+    /// doesn't exist yet.
+    pub fn term(&self, len: usize) -> Option<u32> {
+        let offset = self.offset();
+
+        match self.snapshot.as_ref() {
+            _ if len == 0 => Some(0),
+            Some(snapshot) if len == offset => Some(snapshot.term),
+            _ if len < offset => None,
+            _ => self.log.get(len - offset - 1).map(|entry| entry.term),
+        }
+    }
+
+    /// How many entries precede the first one of the term entry `len - 1` is from, as far back
+    /// as the snapshot.
+    pub fn term_start(&self, len: usize) -> usize {
+        let term = self.term(len);
+
+        let mut start = len;
+        while start > self.offset() && self.term(start - 1) == term {
+            start -= 1;
+        }
+
+        start
+    }
+
+    /// The length of the log up to its last entry of `term` among the first `len`, if there is
+    /// one that wasn't compacted.
+    pub fn term_end(&self, term: u32, len: usize) -> Option<usize> {
+        let mut end = len.min(self.len());
+
+        while end > self.offset() {
+            match self.term(end) {
+                Some(found) if found == term => return Some(end),
+                Some(found) if found < term => return None,
+                _ => end -= 1,
+            }
+        }
+
+        None
+    }
+
+    pub fn entry(&self, index: usize) -> Option<&Log<C>> {
+        self.log.get(index.checked_sub(self.offset())?)
+    }
+
+    /// Every entry from `index` on, which must not be compacted.
+    pub fn entries(&self, index: usize) -> &[Log<C>] {
+        &self.log[index - self.offset()..]
+    }
+
+    pub fn push(&mut self, entry: Log<C>) {
+        self.log.push(entry);
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.log.truncate(len - self.offset());
+        self.saved_len = self.saved_len.min(len);
+    }
+
+    /// Writes whatever changed since the last call to storage, and waits for it to be durable.
+    pub fn persist(&mut self) {
+        let state = HardState {
+            term: self.term,
+            voted_for: self.voted_for.clone(),
+            commit_len: self.commit_len,
+        };
+
+        if state != self.saved {
+            self.storage
+                .save(&state)
+                .expect("Failed to persist raft state");
+            self.saved = state;
+        }
+
+        if self.stored_len > self.saved_len || self.len() > self.saved_len {
+            let unsaved = &self.log[self.saved_len - self.offset()..];
+            self.storage
+                .append(self.saved_len, unsaved)
+                .expect("Failed to persist raft log");
+
+            self.saved_len = self.len();
+            self.stored_len = self.len();
+        }
+
+        self.storage.sync().expect("Failed to sync raft state");
+    }
+
+    /// Replaces the first `len` entries, which must be committed, with a snapshot of the
+    /// state machine they produce.
+    pub fn compact(&mut self, len: usize, data: Vec<u8>) {
+        let term = self.term(len).expect("Compacting past the end of the log");
+        let config = self.config(len).map(|(_, config)| config);
+        self.persist();
+
+        self.log.drain(..len - self.offset());
+        self.snapshot = Some(Snapshot {
+            len,
+            term,
+            config,
+            data,
+        });
+
+        self.save_snapshot();
+    }
+
+    /// Replaces the log with a snapshot from the leader, keeping whatever follows it if the
+    /// log agrees with the snapshot.
+    pub fn install(&mut self, snapshot: Snapshot) {
+        if self.term(snapshot.len) == Some(snapshot.term) {
+            self.log.drain(..snapshot.len - self.offset());
+            self.saved_len = self.saved_len.max(snapshot.len);
+        } else {
+            self.log.clear();
+            self.saved_len = snapshot.len;
+        }
+
+        self.stored_len = self.stored_len.max(snapshot.len);
+        self.commit_len = self.commit_len.max(snapshot.len);
+        self.snapshot = Some(snapshot);
+
+        self.save_snapshot();
+        self.persist();
+    }
+
+    /// The latest configuration in the first `len` entries, and the length of the log up to
+    /// it, or `None` if it was never changed from the initial one.
+    pub fn config(&self, len: usize) -> Option<(usize, Config)> {
+        let offset = self.offset();
+
+        let entries = self.log[..len - offset].iter().enumerate().rev();
+        for (index, log) in entries {
+            if let Entry::Config(config) = &log.entry {
+                return Some((offset + index + 1, config.clone()));
+            }
+        }
+
+        let snapshot = self.snapshot.as_ref()?;
+        Some((snapshot.len, snapshot.config.clone()?))
+    }
 
     pub fn last_log_term(&self) -> Option<u32> {
-        self.log.last().map(|last| last.term)
+        self.log
+            .last()
+            .map(|last| last.term)
+            .or(self.snapshot.as_ref().map(|snapshot| snapshot.term))
+    }
+
+    fn save_snapshot(&mut self) {
+        if let Some(snapshot) = self.snapshot.as_ref() {
+            self.storage
+                .snapshot(snapshot)
+                .expect("Failed to persist raft snapshot");
+        }
     }
 }
 
+/// A read that waits for its read index, then for the state machine to catch up to it.
+#[derive(Clone, Debug)]
+pub struct Read {
+    pub id: u64,
+    /// The node that asked for it, which the leader tells the read index.
+    pub origin: String,
+    pub term: u32,
+    /// The heartbeat round a quorum has to acknowledge before the leader sets the index.
+    pub round: u64,
+    pub index: Option<usize>,
+}
+
+/// A command this node proposed, until it knows whether it committed.
+#[derive(Clone, Debug)]
+pub struct Proposed {
+    pub id: u64,
+    /// The term of its entry, or the term it was forwarded in while it's unknown.
+    pub term: u32,
+    /// Where it is in the log, once the leader appended it.
+    pub index: Option<usize>,
+}
+
 #[derive(Clone, Debug)]
 pub struct TransientState {
     pub role: Role,
     pub leader: Option<String>,
 
     pub votes_received: HashSet<String>,
+    // followers the leader heard from since it last checked for a quorum
+    pub active: HashSet<String>,
 
+    // where the next append to each follower starts, and how much of its log is known to match
     pub sent_len: HashMap<String, usize>,
     pub acked_len: HashMap<String, usize>,
+    // followers that acknowledged an append this term, and so can be sent entries back to back
+    pub matched: HashSet<String>,
+    // the lengths each follower will acknowledge, for appends not answered yet, and when they
+    // were sent
+    pub in_flight: HashMap<String, VecDeque<(usize, Duration)>>,
+    pub last_heartbeat: Duration,
+    // how much of the snapshot each follower has received
+    pub snapshot_sent: HashMap<String, usize>,
+    // the snapshot being received from the leader
+    pub incoming: Option<Snapshot>,
 
     pub consumed: usize,
+
+    // heartbeat rounds the leader sent, and the latest each follower acknowledged
+    pub round: u64,
+    pub round_sent: VecDeque<(u64, Duration)>,
+    pub heard: HashMap<String, u64>,
+    pub lease_until: Duration,
+    // the first round that can extend the lease, as those sent while handing over can't
+    pub lease_round: u64,
+
+    pub reads: Vec<Read>,
+    pub failed_reads: VecDeque<u64>,
+
+    pub proposals: Vec<Proposed>,
+    // proposals whose index was committed, and whether it's theirs, to report in that order,
+    // along with those this node lost track of, which it can't tell
+    pub settled: VecDeque<(u64, Option<bool>)>,
+
+    // the node leadership is being handed to, and when that started
+    pub transfer: Option<(String, Duration)>,
 }
 
 impl Default for TransientState {
@@ -74,9 +367,25 @@ impl Default for TransientState {
             role: Role::Follower,
             leader: None,
             votes_received: HashSet::new(),
+            active: HashSet::new(),
             sent_len: HashMap::new(),
             acked_len: HashMap::new(),
+            matched: HashSet::new(),
+            in_flight: HashMap::new(),
+            last_heartbeat: Duration::ZERO,
+            snapshot_sent: HashMap::new(),
+            incoming: None,
             consumed: 0,
+            round: 0,
+            round_sent: VecDeque::new(),
+            heard: HashMap::new(),
+            lease_until: Duration::ZERO,
+            lease_round: 0,
+            reads: Vec::new(),
+            failed_reads: VecDeque::new(),
+            proposals: Vec::new(),
+            settled: VecDeque::new(),
+            transfer: None,
         }
     }
 }
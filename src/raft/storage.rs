@@ -0,0 +1,204 @@
+use std::{cell::RefCell, io, rc::Rc};
+
+use serde::{Deserialize, Serialize};
+
+use super::{Config, Log};
+
+/// The part of Raft's state, besides the log, that must survive a restart.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HardState {
+    pub term: u32,
+    pub voted_for: Option<String>,
+    pub commit_len: usize,
+}
+
+/// A prefix of the log, compacted into the state machine it produces.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// How many log entries it covers.
+    pub len: usize,
+    /// The term of the last entry it covers.
+    pub term: u32,
+    /// The configuration in effect at that point, if it changed from the initial one.
+    pub config: Option<Config>,
+    pub data: Vec<u8>,
+}
+
+/// Everything a `Storage` holds: the log is whatever follows the snapshot, if any.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Saved<C> {
+    pub state: HardState,
+    pub snapshot: Option<Snapshot>,
+    pub log: Vec<Log<C>>,
+}
+
+impl<C> Default for Saved<C> {
+    fn default() -> Self {
+        Self {
+            state: HardState::default(),
+            snapshot: None,
+            log: Vec::new(),
+        }
+    }
+}
+
+impl<C> Saved<C> {
+    pub(crate) fn offset(&self) -> usize {
+        self.snapshot.as_ref().map_or(0, |snapshot| snapshot.len)
+    }
+
+    pub(crate) fn append(&mut self, index: usize, entries: impl IntoIterator<Item = Log<C>>) {
+        let offset = self.offset();
+
+        // entries the snapshot already covers are committed, and can't differ
+        let skip = offset.saturating_sub(index);
+        self.log.truncate(index.saturating_sub(offset));
+        self.log.extend(entries.into_iter().skip(skip));
+    }
+
+    pub(crate) fn compact(&mut self, snapshot: Snapshot) {
+        let compacted = snapshot
+            .len
+            .saturating_sub(self.offset())
+            .min(self.log.len());
+        self.log.drain(..compacted);
+        self.snapshot = Some(snapshot);
+    }
+}
+
+/// Where Raft keeps its hard state, log and snapshot. Writes may be buffered until `sync`,
+/// which Raft calls before sending anything that depends on them.
+pub trait Storage<C> {
+    /// Everything saved so far, to resume from after a restart.
+    fn load(&mut self) -> io::Result<Saved<C>>;
+    fn save(&mut self, state: &HardState) -> io::Result<()>;
+    /// Replaces every entry from `index` on with `entries`.
+    fn append(&mut self, index: usize, entries: &[Log<C>]) -> io::Result<()>;
+    /// Replaces the previous snapshot and every entry it covers.
+    fn snapshot(&mut self, snapshot: &Snapshot) -> io::Result<()>;
+    fn sync(&mut self) -> io::Result<()>;
+}
+
+/// Keeps everything in memory. Clones share their contents, so a node rebuilt around a
+/// clone recovers whatever the previous one saved, as if it had restarted.
+pub struct MemoryStorage<C> {
+    inner: Rc<RefCell<Saved<C>>>,
+}
+
+impl<C> MemoryStorage<C> {
+    pub fn new() -> Self {
+        Self {
+            inner: Rc::new(RefCell::new(Saved::default())),
+        }
+    }
+}
+
+impl<C> Clone for MemoryStorage<C> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+impl<C> Default for MemoryStorage<C> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<C> Storage<C> for MemoryStorage<C>
+where
+    C: Clone,
+{
+    fn load(&mut self) -> io::Result<Saved<C>> {
+        Ok(self.inner.borrow().clone())
+    }
+
+    fn save(&mut self, state: &HardState) -> io::Result<()> {
+        self.inner.borrow_mut().state = state.clone();
+        Ok(())
+    }
+
+    fn append(&mut self, index: usize, entries: &[Log<C>]) -> io::Result<()> {
+        self.inner
+            .borrow_mut()
+            .append(index, entries.iter().cloned());
+        Ok(())
+    }
+
+    fn snapshot(&mut self, snapshot: &Snapshot) -> io::Result<()> {
+        self.inner.borrow_mut().compact(snapshot.clone());
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryStorage;
+    use crate::raft::{
+        AppendRequest, Committed, Delivery, Entry, Log, Raft, RaftConfig, Rpc, RpcType,
+    };
+
+    fn raft(storage: &MemoryStorage<String>) -> Raft<String> {
+        let nodes = ["n0", "n1", "n2"].map(String::from).to_vec();
+        Raft::new("n0".to_string(), nodes, RaftConfig::default()).storage(storage.clone())
+    }
+
+    #[test]
+    fn restart() {
+        let storage = MemoryStorage::new();
+
+        let mut before = raft(&storage);
+        let rpc = Rpc {
+            term: 2,
+            payload: RpcType::AppendRequest(AppendRequest {
+                leader: "n1".to_string(),
+                prefix_len: 0,
+                prefix_term: 0,
+                commit_len: 1,
+                round: 0,
+                suffix: vec![
+                    Log {
+                        term: 2,
+                        entry: Entry::Command("a".to_string()),
+                    },
+                    Log {
+                        term: 2,
+                        entry: Entry::Command("b".to_string()),
+                    },
+                ],
+            }),
+        };
+        before.process("n1".to_string(), rpc);
+        drop(before);
+
+        let mut after = raft(&storage);
+        assert_eq!(after.log().len(), 2);
+        assert_eq!(after.consume(), Some(Committed::Command("a".to_string())));
+        assert_eq!(after.consume(), None);
+
+        // a stale leader from before the restart is rejected
+        let rpc = Rpc {
+            term: 1,
+            payload: RpcType::AppendRequest(AppendRequest {
+                leader: "n2".to_string(),
+                prefix_len: 0,
+                prefix_term: 0,
+                commit_len: 0,
+                round: 0,
+                suffix: Vec::new(),
+            }),
+        };
+        let Some(Delivery::Unicast(_, reply)) = after.process("n2".to_string(), rpc) else {
+            panic!("expected a reply");
+        };
+        assert!(
+            matches!(reply.payload, RpcType::AppendResponse(response) if response.ack.is_none())
+        );
+    }
+}
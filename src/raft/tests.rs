@@ -0,0 +1,1114 @@
+use std::{
+    cell::RefCell,
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    ops::Bound,
+    rc::Rc,
+    time::Duration,
+};
+
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+use super::{
+    AppendRequest, AppendResponse, Committed, Config, Delivery, Entry, InstallSnapshot,
+    MemoryStorage, Proposal, Raft, RaftConfig, Rejection, Role, Rpc, RpcType, Status, VoteRequest,
+};
+use crate::{Clock, VirtualClock};
+
+const STEP: Duration = Duration::from_millis(10);
+
+/// A few Raft instances on a perfect network, stepped in virtual time.
+struct Cluster {
+    clock: VirtualClock,
+    nodes: BTreeMap<String, Raft<String>>,
+    down: BTreeSet<String>,
+    // nodes that keep running, but can't reach anyone
+    isolated: BTreeSet<String>,
+    queue: VecDeque<(String, String, Rpc<String>)>,
+    applied: BTreeMap<String, Vec<String>>,
+    // reads served on each node, with how many commands it had applied by then
+    reads: BTreeMap<String, Vec<(u64, usize)>>,
+    // proposals each node made, and whether they were applied or lost
+    proposals: BTreeMap<String, Vec<(u64, bool)>>,
+    next_proposal: u64,
+    // appends each node rejected
+    rejected: BTreeMap<String, usize>,
+}
+
+impl Cluster {
+    fn new(count: usize) -> Self {
+        Self::with(count, |raft| raft)
+    }
+
+    fn with(count: usize, build: impl Fn(Raft<String>) -> Raft<String>) -> Self {
+        Self::build(count, RaftConfig::default(), build)
+    }
+
+    fn configured(count: usize, settings: RaftConfig) -> Self {
+        Self::build(count, settings, |raft| raft)
+    }
+
+    fn build(
+        count: usize,
+        settings: RaftConfig,
+        build: impl Fn(Raft<String>) -> Raft<String>,
+    ) -> Self {
+        let clock = VirtualClock::new();
+        let ids = (0..count).map(|i| format!("n{}", i)).collect::<Vec<_>>();
+
+        let nodes = ids
+            .iter()
+            .zip(0..)
+            .map(|(id, seed)| {
+                let raft = Raft::new(id.clone(), ids.clone(), settings.clone())
+                    .clock(Rc::new(clock.clone()))
+                    .seed(seed);
+                (id.clone(), build(raft))
+            })
+            .collect();
+
+        Self {
+            clock,
+            nodes,
+            down: BTreeSet::new(),
+            isolated: BTreeSet::new(),
+            queue: VecDeque::new(),
+            applied: BTreeMap::new(),
+            reads: BTreeMap::new(),
+            proposals: BTreeMap::new(),
+            next_proposal: 0,
+            rejected: BTreeMap::new(),
+        }
+    }
+
+    // starts a node outside the cluster, which only learns it's a member through the log
+    fn join(&mut self, id: &str) {
+        let others = self.nodes.keys().cloned().collect();
+        let raft = Raft::new(id.to_string(), others, RaftConfig::default())
+            .clock(Rc::new(self.clock.clone()))
+            .seed(self.nodes.len() as u64);
+        self.nodes.insert(id.to_string(), raft);
+    }
+
+    fn raft(&mut self, id: &str) -> &mut Raft<String> {
+        self.nodes.get_mut(id).unwrap()
+    }
+
+    fn leader(&self) -> Option<String> {
+        self.nodes
+            .iter()
+            .find(|(id, raft)| raft.is_leader() && !self.down.contains(*id))
+            .map(|(id, _)| id.clone())
+    }
+
+    fn apply(&mut self, node: &str, command: &str) -> Proposal<String> {
+        self.next_proposal += 1;
+        let id = self.next_proposal;
+        let proposal = self.raft(node).apply(id, command.to_string());
+
+        self.send(node, proposal.clone().delivery());
+        proposal
+    }
+
+    fn send(&mut self, from: &str, delivery: Option<Delivery<String>>) {
+        let messages = match delivery {
+            None => Vec::new(),
+            Some(Delivery::Unicast(to, rpc)) => vec![(to, rpc)],
+            Some(Delivery::Multicast(messages)) => messages,
+            Some(Delivery::Broadcast(rpc)) => self.nodes[from]
+                .others()
+                .map(|to| (to.clone(), rpc.clone()))
+                .collect(),
+        };
+
+        for (to, rpc) in messages {
+            self.queue.push_back((from.to_string(), to, rpc));
+        }
+    }
+
+    fn step(&mut self) {
+        self.clock.advance(STEP);
+
+        let ids = self.nodes.keys().cloned().collect::<Vec<_>>();
+        let up = ids
+            .iter()
+            .filter(|id| !self.down.contains(*id))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        for id in up.iter() {
+            let delivery = self.raft(id).tick();
+            self.send(id, delivery);
+        }
+
+        while let Some((from, to, rpc)) = self.queue.pop_front() {
+            let lost = [&from, &to]
+                .into_iter()
+                .any(|node| self.down.contains(node) || self.isolated.contains(node));
+
+            if lost {
+                continue;
+            }
+
+            if let RpcType::AppendResponse(response) = &rpc.payload {
+                if response.ack.is_none() {
+                    *self.rejected.entry(from.clone()).or_default() += 1;
+                }
+            }
+
+            let delivery = self.raft(&to).process(from, rpc);
+            self.send(&to, delivery);
+        }
+
+        for id in ids.iter() {
+            while let Some(committed) = self.raft(id).consume() {
+                let applied = self.applied.entry(id.clone()).or_default();
+                match committed {
+                    Committed::Command(command) => applied.push(command),
+                    Committed::Read(read) => {
+                        let served = (read, applied.len());
+                        self.reads.entry(id.clone()).or_default().push(served);
+                    }
+                    Committed::Applied(proposal) | Committed::Lost(proposal) => {
+                        let outcome = (proposal, matches!(committed, Committed::Applied(_)));
+                        self.proposals.entry(id.clone()).or_default().push(outcome);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    fn run_for(&mut self, duration: Duration) {
+        let until = self.clock.now() + duration;
+        while self.clock.now() < until {
+            self.step();
+        }
+    }
+}
+
+fn config(nodes: &[&str]) -> Config {
+    Config::new(nodes.iter().map(|node| node.to_string()))
+}
+
+#[test]
+fn joint_quorum() {
+    let mut joint = config(&["n0", "n1", "n2"]);
+    joint.next = Some(config(&["n2", "n3", "n4"]).voters);
+
+    assert!(!joint.quorum(|node| ["n0", "n1"].contains(&node.as_str())));
+    assert!(!joint.quorum(|node| ["n3", "n4"].contains(&node.as_str())));
+    assert!(joint.quorum(|node| ["n1", "n2", "n3"].contains(&node.as_str())));
+
+    // an even number of voters needs more than half of them
+    let even = config(&["n0", "n1", "n2", "n3"]);
+    assert!(!even.quorum(|node| ["n0", "n1"].contains(&node.as_str())));
+    assert!(even.quorum(|node| node != "n0"));
+}
+
+#[test]
+fn shrink_cluster() {
+    let mut cluster = Cluster::new(5);
+    cluster.run_for(Duration::from_secs(5));
+
+    let leader = cluster.leader().unwrap();
+    let voters = ["n0", "n1", "n2"];
+    let delivery = cluster
+        .raft(&leader)
+        .reconfigure(voters.map(String::from))
+        .unwrap();
+    cluster.send(&leader, Some(delivery));
+    cluster.run_for(Duration::from_secs(1));
+
+    for node in voters {
+        assert_eq!(cluster.raft(node).config(), &config(&voters));
+    }
+
+    // with the others gone, the three left are a cluster of their own
+    cluster.down.extend(["n3", "n4"].map(String::from));
+    cluster.run_for(Duration::from_secs(5));
+
+    let leader = cluster.leader().unwrap();
+    assert!(voters.contains(&leader.as_str()));
+
+    cluster.apply(&leader, "x");
+    cluster.run_for(Duration::from_secs(1));
+
+    for node in voters {
+        assert_eq!(cluster.applied[node], vec!["x".to_string()]);
+    }
+}
+
+#[test]
+fn learner_is_promoted_once_caught_up() {
+    let mut cluster = Cluster::new(3);
+    cluster.run_for(Duration::from_secs(5));
+
+    let leader = cluster.leader().unwrap();
+    for command in ["a", "b", "c"] {
+        cluster.apply(&leader, command);
+    }
+    cluster.run_for(Duration::from_secs(1));
+
+    // it can't be promoted before it even joins
+    cluster.join("n3");
+    assert!(cluster.raft(&leader).promote("n3".to_string()).is_none());
+
+    let delivery = cluster.raft(&leader).add_learner("n3".to_string());
+    cluster.send(&leader, delivery);
+    cluster.run_for(Duration::from_secs(1));
+
+    assert_eq!(cluster.applied["n3"], cluster.applied[&leader]);
+    assert!(cluster.raft("n3").config().learners.contains("n3"));
+
+    // with the learner down as well as a voter, the two voters left still make a majority
+    let follower = ["n0", "n1", "n2"]
+        .into_iter()
+        .find(|node| *node != leader)
+        .unwrap();
+    cluster.down.extend([follower, "n3"].map(String::from));
+    cluster.apply(&leader, "d");
+    cluster.run_for(Duration::from_secs(1));
+    assert_eq!(cluster.applied[&leader].last().unwrap(), "d");
+
+    // and it never stood for election, however long it heard nothing
+    cluster.down.clear();
+    cluster.run_for(Duration::from_secs(5));
+    assert_eq!(cluster.leader(), Some(leader.clone()));
+    assert_eq!(cluster.applied["n3"], cluster.applied[&leader]);
+
+    let delivery = cluster.raft(&leader).promote("n3".to_string());
+    cluster.send(&leader, delivery.clone());
+    assert!(delivery.is_some());
+    cluster.run_for(Duration::from_secs(1));
+
+    for node in ["n0", "n1", "n2", "n3"] {
+        assert_eq!(
+            cluster.raft(node).config(),
+            &config(&["n0", "n1", "n2", "n3"])
+        );
+    }
+}
+
+#[test]
+fn follower_read_sees_completed_write() {
+    let mut cluster = Cluster::new(3);
+    cluster.run_for(Duration::from_secs(5));
+
+    let leader = cluster.leader().unwrap();
+    let follower = ["n0", "n1"]
+        .into_iter()
+        .find(|node| *node != leader)
+        .unwrap();
+
+    cluster.apply(&leader, "x");
+    cluster.step();
+    assert_eq!(cluster.applied[&leader], vec!["x".to_string()]);
+
+    // the write completed on the leader, so the follower can't serve the read before it
+    // applies it as well
+    let delivery = cluster.raft(follower).read(7);
+    cluster.send(follower, delivery);
+    cluster.run_for(Duration::from_secs(1));
+
+    assert_eq!(cluster.reads[follower], vec![(7, 1)]);
+}
+
+#[test]
+fn pre_vote_keeps_isolated_node_out() {
+    let mut cluster = Cluster::with(3, |raft| raft.pre_vote(true));
+    cluster.run_for(Duration::from_secs(5));
+
+    let leader = cluster.leader().unwrap();
+    let term = cluster.raft(&leader).term();
+    let follower = ["n0", "n1"]
+        .into_iter()
+        .find(|node| *node != leader)
+        .unwrap();
+
+    // alone, the follower never wins a pre-vote, so it never starts an election
+    cluster.isolated.insert(follower.to_string());
+    cluster.run_for(Duration::from_secs(10));
+    assert_eq!(cluster.raft(follower).term(), term);
+
+    cluster.isolated.clear();
+    cluster.run_for(Duration::from_secs(1));
+    assert_eq!(cluster.leader(), Some(leader.clone()));
+    assert_eq!(cluster.raft(&leader).term(), term);
+}
+
+#[test]
+fn check_quorum_deposes_isolated_leader() {
+    let mut cluster = Cluster::with(3, |raft| raft.pre_vote(true).check_quorum(true));
+    cluster.run_for(Duration::from_secs(5));
+
+    let leader = cluster.leader().unwrap();
+    cluster.isolated.insert(leader.clone());
+    cluster.run_for(Duration::from_secs(5));
+
+    assert!(!cluster.raft(&leader).is_leader());
+
+    let others = cluster.nodes.iter().filter(|(id, _)| **id != leader);
+    assert_eq!(others.filter(|(_, raft)| raft.is_leader()).count(), 1);
+}
+
+#[test]
+fn granting_a_vote_holds_off_elections() {
+    let clock = VirtualClock::new();
+    let nodes = ["n0", "n1", "n2"].map(String::from).to_vec();
+    let settings = RaftConfig {
+        election_timeout: Duration::from_secs(1)..Duration::from_secs(1),
+        ..RaftConfig::default()
+    };
+    let mut raft =
+        Raft::<String>::new("n1".to_string(), nodes, settings).clock(Rc::new(clock.clone()));
+
+    clock.advance(Duration::from_millis(900));
+    let vote = Rpc {
+        term: 2,
+        payload: RpcType::VoteRequest(VoteRequest {
+            candidate: "n2".to_string(),
+            last_log_index: 0,
+            last_log_term: 0,
+            transfer: false,
+        }),
+    };
+    raft.process("n2".to_string(), vote);
+    assert_eq!(raft.term(), 2);
+
+    // n2 may well win, so it gets a full timeout from the vote to send its first append
+    clock.advance(Duration::from_millis(900));
+    assert!(raft.tick().is_none());
+
+    clock.advance(Duration::from_millis(200));
+    assert!(matches!(raft.tick(), Some(Delivery::Broadcast(_))));
+}
+
+#[test]
+fn transfer_leadership() {
+    let mut cluster = Cluster::with(3, |raft| raft.pre_vote(true).check_quorum(true));
+    cluster.run_for(Duration::from_secs(5));
+
+    let leader = cluster.leader().unwrap();
+    let target = ["n0", "n1"]
+        .into_iter()
+        .find(|node| *node != leader)
+        .unwrap();
+
+    cluster.apply(&leader, "x");
+    let delivery = cluster
+        .raft(&leader)
+        .transfer_leadership(target.to_string());
+    cluster.send(&leader, delivery);
+
+    // no proposals while handing over
+    let proposal = cluster.apply(&leader, "y");
+    assert!(matches!(
+        proposal,
+        Proposal::Rejected(Rejection::Transferring)
+    ));
+
+    cluster.run_for(Duration::from_millis(500));
+    assert_eq!(cluster.leader().as_deref(), Some(target));
+    assert_eq!(cluster.applied[target], vec!["x".to_string()]);
+}
+
+#[test]
+fn transfer_times_out() {
+    let mut cluster = Cluster::new(3);
+    cluster.run_for(Duration::from_secs(5));
+
+    let leader = cluster.leader().unwrap();
+    let target = ["n0", "n1"]
+        .into_iter()
+        .find(|node| *node != leader)
+        .unwrap();
+    cluster.isolated.insert(target.to_string());
+
+    let delivery = cluster
+        .raft(&leader)
+        .transfer_leadership(target.to_string());
+    cluster.send(&leader, delivery);
+    let proposal = cluster.apply(&leader, "x");
+    assert!(matches!(proposal, Proposal::Rejected(_)));
+
+    // the target never catches up, so the leader gives up and takes proposals again
+    cluster.run_for(Duration::from_millis(1500));
+    assert_eq!(cluster.leader(), Some(leader.clone()));
+
+    let proposal = cluster.apply(&leader, "x");
+    assert!(matches!(proposal, Proposal::Accepted { .. }));
+}
+
+#[test]
+fn aborted_transfer_does_not_restore_lease() {
+    let mut cluster = Cluster::with(3, |raft| raft.lease(Duration::from_millis(500)));
+    cluster.run_for(Duration::from_secs(5));
+
+    let leader = cluster.leader().unwrap();
+    let target = ["n0", "n1"]
+        .into_iter()
+        .find(|node| *node != leader)
+        .unwrap();
+    cluster.isolated.insert(target.to_string());
+
+    let delivery = cluster
+        .raft(&leader)
+        .transfer_leadership(target.to_string());
+    cluster.send(&leader, delivery);
+
+    // the third node keeps acknowledging heartbeats, but the target could have won an election
+    // that the leader, cut off by the time it gives up, never hears of
+    cluster.run_for(Duration::from_millis(900));
+    cluster.isolated.insert(leader.clone());
+    while cluster.raft(&leader).transient.transfer.is_some() {
+        cluster.step();
+    }
+
+    // so the read has to wait for a round of heartbeats
+    let delivery = cluster.raft(&leader).read(7);
+    assert!(matches!(delivery, Some(Delivery::Multicast(_))));
+}
+
+#[test]
+fn appends_are_batched() {
+    let mut cluster = Cluster::configured(
+        3,
+        RaftConfig {
+            max_entries: 2,
+            max_in_flight: 2,
+            ..RaftConfig::default()
+        },
+    );
+    cluster.run_for(Duration::from_secs(5));
+
+    // the appends for these are lost, so the followers fall behind
+    let leader = cluster.leader().unwrap();
+    let commands = (0..10).map(|i| i.to_string()).collect::<Vec<_>>();
+    for command in commands.iter() {
+        cluster.raft(&leader).apply(0, command.clone());
+    }
+
+    // and are caught up a few entries at a time, as fast as they acknowledge them
+    cluster.clock.advance(Duration::from_millis(100));
+    let delivery = cluster.raft(&leader).tick();
+    let Some(Delivery::Multicast(messages)) = delivery.clone() else {
+        panic!("expected appends");
+    };
+    let appends = messages.iter().filter_map(|(_, rpc)| match &rpc.payload {
+        RpcType::AppendRequest(request) => Some(request.suffix.len()),
+        _ => None,
+    });
+    assert_eq!(appends.collect::<Vec<_>>(), vec![2, 2, 2, 2]);
+
+    cluster.send(&leader, delivery);
+    cluster.run_for(Duration::from_secs(1));
+    for node in ["n0", "n1", "n2"] {
+        assert_eq!(cluster.applied[node], commands);
+    }
+}
+
+#[test]
+fn slow_appends_are_not_resent() {
+    let mut cluster = Cluster::configured(
+        3,
+        RaftConfig {
+            max_entries: 1,
+            max_in_flight: 2,
+            ..RaftConfig::default()
+        },
+    );
+    cluster.run_for(Duration::from_secs(5));
+    let leader = cluster.leader().unwrap();
+
+    // line up with the leader's heartbeats
+    while cluster.raft(&leader).tick().is_none() {
+        cluster.clock.advance(Duration::from_millis(1));
+    }
+
+    // the followers are slow to answer, so these fill the window and stay in flight
+    cluster.clock.advance(Duration::from_millis(40));
+    let commands = (0..4).map(|i| i.to_string()).collect::<Vec<_>>();
+    let mut held = Vec::new();
+    for command in commands.iter() {
+        held.push(cluster.raft(&leader).apply(0, command.clone()).delivery());
+    }
+
+    let suffixes = |delivery: Option<Delivery<String>>| {
+        let Some(Delivery::Multicast(messages)) = delivery else {
+            panic!("expected appends");
+        };
+        messages
+            .into_iter()
+            .filter_map(|(_, rpc)| match rpc.payload {
+                RpcType::AppendRequest(request) => Some(request.suffix.len()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // the next heartbeat comes too soon to count them as lost
+    cluster.clock.advance(Duration::from_millis(10));
+    let delivery = cluster.raft(&leader).tick();
+    assert_eq!(suffixes(delivery), vec![0, 0]);
+
+    // but they're sent again once they went unanswered for a whole heartbeat
+    cluster.clock.advance(Duration::from_millis(50));
+    let delivery = cluster.raft(&leader).tick();
+    assert_eq!(suffixes(delivery.clone()), vec![1, 1, 1, 1]);
+
+    cluster.send(&leader, delivery);
+    for delivery in held {
+        cluster.send(&leader, delivery);
+    }
+    cluster.run_for(Duration::from_secs(1));
+    for node in ["n0", "n1", "n2"] {
+        assert_eq!(cluster.applied[node], commands);
+    }
+}
+
+#[test]
+fn diverged_follower_backtracks_by_term() {
+    // without pre-votes, the old leader may depose the new one as soon as it's elected
+    let mut cluster = Cluster::with(3, |raft| raft.pre_vote(true));
+    cluster.run_for(Duration::from_secs(5));
+
+    // the old leader gets entries nobody else has
+    let old = cluster.leader().unwrap();
+    cluster.isolated.insert(old.clone());
+    for i in 0..50 {
+        cluster.apply(&old, &format!("lost {}", i));
+    }
+    cluster.run_for(Duration::from_secs(5));
+
+    // while the others commit entries of their own
+    let leader = cluster
+        .nodes
+        .iter()
+        .find(|(id, raft)| **id != old && raft.is_leader())
+        .map(|(id, _)| id.clone())
+        .unwrap();
+    let commands = (0..50).map(|i| i.to_string()).collect::<Vec<_>>();
+    for command in commands.iter() {
+        cluster.apply(&leader, command);
+    }
+    cluster.run_for(Duration::from_secs(1));
+
+    // the third node takes over, starting out as if the old leader's log matched its own
+    let third = cluster
+        .nodes
+        .keys()
+        .find(|id| **id != old && **id != leader)
+        .cloned()
+        .unwrap();
+    cluster.down.insert(leader);
+    cluster.isolated.clear();
+    while !cluster.raft(&third).is_leader() {
+        assert!(cluster.clock.now() < Duration::from_secs(30));
+        cluster.step();
+    }
+
+    // correcting that takes a round trip per term, rather than one per entry, and the probes
+    // go out back to back rather than a heartbeat apart
+    let rejected = cluster.rejected.get(&old).copied().unwrap_or(0);
+    let started = cluster.clock.now();
+    while cluster.applied.get(&old) != Some(&commands) {
+        assert!(cluster.clock.now() - started <= Duration::from_millis(60));
+        cluster.step();
+    }
+    assert!(cluster.rejected[&old] - rejected <= 3);
+
+    // and the old leader learns that what it took was lost
+    let lost = cluster.proposals[&old]
+        .iter()
+        .filter(|(_, applied)| !applied);
+    assert_eq!(lost.count(), 50);
+}
+
+#[test]
+fn proposals_are_settled() {
+    let mut cluster = Cluster::new(3);
+
+    let proposal = cluster.apply("n0", "x");
+    assert!(matches!(proposal, Proposal::Rejected(Rejection::NoLeader)));
+
+    cluster.run_for(Duration::from_secs(5));
+    let leader = cluster.leader().unwrap();
+    let follower = ["n0", "n1"]
+        .into_iter()
+        .find(|node| *node != leader)
+        .unwrap();
+
+    let proposal = cluster.apply(follower, "y");
+    assert!(matches!(proposal, Proposal::Forwarded(_)));
+    let proposal = cluster.apply(&leader, "z");
+    assert!(matches!(proposal, Proposal::Accepted { .. }));
+
+    cluster.run_for(Duration::from_secs(1));
+    assert_eq!(cluster.proposals[follower], vec![(2, true)]);
+    assert_eq!(cluster.proposals[&leader], vec![(3, true)]);
+}
+
+#[test]
+fn status_follows_elections_and_replication() {
+    let changes = Rc::new(RefCell::new(Vec::<Status>::new()));
+    let mut cluster = Cluster::with(3, |raft| {
+        let changes = changes.clone();
+        raft.on_change(move |status| changes.borrow_mut().push(status.clone()))
+    });
+
+    cluster.run_for(Duration::from_secs(5));
+    let leader = cluster.leader().unwrap();
+
+    // every node reports the leader it ends up following, the leader itself included
+    let changes = changes.borrow();
+    for node in ["n0", "n1", "n2"] {
+        let last = changes
+            .iter()
+            .rev()
+            .find(|status| status.id == node)
+            .unwrap();
+        assert_eq!(last.leader.as_ref(), Some(&leader));
+        assert_eq!(last.role == Role::Leader, node == leader);
+    }
+
+    cluster.apply(&leader, "x");
+    cluster.run_for(Duration::from_secs(1));
+
+    let status = cluster.raft(&leader).status();
+    assert_eq!(status.role, Role::Leader);
+    assert_eq!(status.applied_len, status.commit_len);
+    assert_eq!(status.progress.len(), 2);
+    for progress in status.progress.values() {
+        assert_eq!(progress.acked_len, status.commit_len);
+    }
+
+    let follower = ["n0", "n1"]
+        .into_iter()
+        .find(|node| *node != leader)
+        .unwrap();
+    let status = cluster.raft(follower).status();
+    assert_eq!(status.role, Role::Follower);
+    assert_eq!(status.leader, Some(leader));
+    assert!(status.progress.is_empty());
+}
+
+#[test]
+fn leader_deposed_by_response_forgets_it_led() {
+    let changes = Rc::new(RefCell::new(Vec::<Status>::new()));
+    let mut cluster = Cluster::with(3, |raft| {
+        let changes = changes.clone();
+        raft.on_change(move |status| changes.borrow_mut().push(status.clone()))
+    });
+
+    cluster.run_for(Duration::from_secs(5));
+    let leader = cluster.leader().unwrap();
+    let follower = ["n0", "n1"]
+        .into_iter()
+        .find(|node| *node != leader)
+        .unwrap();
+    cluster
+        .raft(&leader)
+        .transfer_leadership(follower.to_string());
+
+    // a follower that moved on to a newer term answers an append from before
+    let term = cluster.raft(&leader).term() + 1;
+    let response = Rpc {
+        term,
+        payload: RpcType::AppendResponse(AppendResponse {
+            follower: follower.to_string(),
+            ack: None,
+            conflict_term: None,
+            conflict_len: 0,
+            round: 0,
+        }),
+    };
+    cluster
+        .raft(&leader)
+        .process(follower.to_string(), response);
+
+    let status = cluster.raft(&leader).status();
+    assert_eq!(status.role, Role::Follower);
+    assert_eq!(status.term, term);
+    assert_eq!(status.leader, None);
+    assert!(cluster.raft(&leader).transient.transfer.is_none());
+
+    let last = changes.borrow().last().cloned().unwrap();
+    assert_eq!(last, status);
+}
+
+#[test]
+fn deposed_leader_does_not_hold_off_elections() {
+    let clock = VirtualClock::new();
+    let nodes = ["n0", "n1", "n2"].map(String::from).to_vec();
+    let mut raft = Raft::<String>::new("n1".to_string(), nodes, RaftConfig::default())
+        .clock(Rc::new(clock.clone()));
+
+    // n1 moves on to term 2 by voting for n2, while n0 still leads term 1
+    let vote = Rpc {
+        term: 2,
+        payload: RpcType::VoteRequest(VoteRequest {
+            candidate: "n2".to_string(),
+            last_log_index: 0,
+            last_log_term: 0,
+            transfer: false,
+        }),
+    };
+    raft.process("n2".to_string(), vote);
+
+    let stale = [
+        RpcType::AppendRequest(AppendRequest {
+            leader: "n0".to_string(),
+            prefix_len: 0,
+            prefix_term: 0,
+            commit_len: 0,
+            round: 0,
+            suffix: Vec::new(),
+        }),
+        RpcType::InstallSnapshot(InstallSnapshot {
+            leader: "n0".to_string(),
+            len: 1,
+            last_term: 1,
+            config: None,
+            offset: 0,
+            data: Vec::new(),
+            done: false,
+        }),
+    ];
+
+    // n2 never follows up, so n1 stands for election however much n0 keeps sending
+    let mut elected = false;
+    for payload in stale.into_iter().cycle().take(200) {
+        clock.advance(STEP);
+        raft.process("n0".to_string(), Rpc { term: 1, payload });
+        elected |= matches!(raft.tick(), Some(Delivery::Broadcast(_)));
+    }
+
+    assert!(elected);
+    assert!(raft.term() > 2);
+}
+
+/// Raft instances on a network that loses, duplicates and reorders messages, and gets
+/// partitioned, while nodes crash and restart from their storage. Everything random, election
+/// timeouts included, comes from the seed, so the seed the checks report when something breaks
+/// replays the run exactly, with `CHAOS_SEED` set to it.
+struct Chaos {
+    seed: u64,
+    rng: StdRng,
+    clock: VirtualClock,
+    pre_vote: bool,
+    check_quorum: bool,
+    storage: BTreeMap<String, MemoryStorage<String>>,
+    nodes: BTreeMap<String, Raft<String>>,
+    down: BTreeSet<String>,
+    // the side of the partition each node is on, while the network is split
+    sides: BTreeMap<String, bool>,
+    // messages in flight, with when they arrive
+    network: Vec<(Duration, String, String, Rpc<String>)>,
+    // the commands each node applied since it last started, snapshots included
+    applied: BTreeMap<String, Vec<String>>,
+    // the longest sequence of commands any node applied, and for each of them, the highest
+    // term of any node when it was first seen applied, which no commit happened after
+    committed: Vec<(String, u32)>,
+    // the node that led each term
+    leaders: BTreeMap<u32, String>,
+    next_command: u64,
+}
+
+impl Chaos {
+    fn new(count: usize, seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let ids = (0..count).map(|i| format!("n{}", i)).collect::<Vec<_>>();
+
+        let mut chaos = Self {
+            seed,
+            pre_vote: rng.gen_bool(0.5),
+            check_quorum: rng.gen_bool(0.5),
+            rng,
+            clock: VirtualClock::new(),
+            storage: BTreeMap::new(),
+            nodes: BTreeMap::new(),
+            down: BTreeSet::new(),
+            sides: BTreeMap::new(),
+            network: Vec::new(),
+            applied: BTreeMap::new(),
+            committed: Vec::new(),
+            leaders: BTreeMap::new(),
+            next_command: 0,
+        };
+
+        for id in ids.iter() {
+            chaos.storage.insert(id.clone(), MemoryStorage::new());
+        }
+
+        for id in ids.iter() {
+            chaos.start(id);
+        }
+
+        chaos
+    }
+
+    // (re)starts a node from whatever its storage holds
+    fn start(&mut self, id: &str) {
+        let ids = self.storage.keys().cloned().collect();
+        let raft = Raft::new(id.to_string(), ids, RaftConfig::default())
+            .clock(Rc::new(self.clock.clone()))
+            .seed(self.rng.gen())
+            .storage(self.storage[id].clone())
+            .pre_vote(self.pre_vote)
+            .check_quorum(self.check_quorum);
+
+        self.nodes.insert(id.to_string(), raft);
+        self.applied.insert(id.to_string(), Vec::new());
+        self.down.remove(id);
+    }
+
+    fn raft(&mut self, id: &str) -> &mut Raft<String> {
+        self.nodes.get_mut(id).unwrap()
+    }
+
+    fn up(&self) -> Vec<String> {
+        self.nodes
+            .keys()
+            .filter(|id| !self.down.contains(*id))
+            .cloned()
+            .collect()
+    }
+
+    fn send(&mut self, from: &str, delivery: Option<Delivery<String>>) {
+        let messages = match delivery {
+            None => Vec::new(),
+            Some(Delivery::Unicast(to, rpc)) => vec![(to, rpc)],
+            Some(Delivery::Multicast(messages)) => messages,
+            Some(Delivery::Broadcast(rpc)) => self.nodes[from]
+                .others()
+                .map(|to| (to.clone(), rpc.clone()))
+                .collect(),
+        };
+
+        for (to, rpc) in messages {
+            if self.rng.gen_bool(0.05) {
+                continue;
+            }
+
+            let copies = if self.rng.gen_bool(0.02) { 2 } else { 1 };
+            for _ in 0..copies {
+                let delay = Duration::from_millis(self.rng.gen_range(1..40));
+                let arrival = self.clock.now() + delay;
+                self.network
+                    .push((arrival, from.to_string(), to.clone(), rpc.clone()));
+            }
+        }
+    }
+
+    fn step(&mut self) {
+        self.clock.advance(STEP);
+        self.disrupt();
+
+        for id in self.up() {
+            let delivery = self.raft(&id).tick();
+            self.send(&id, delivery);
+        }
+
+        // whatever is due arrives in any order
+        let now = self.clock.now();
+        let (mut due, pending) = std::mem::take(&mut self.network)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(arrival, ..)| *arrival <= now);
+        self.network = pending;
+        due.shuffle(&mut self.rng);
+
+        for (_, from, to, rpc) in due {
+            let split = self.sides.get(&from) != self.sides.get(&to);
+            if split || self.down.contains(&to) {
+                continue;
+            }
+
+            let delivery = self.raft(&to).process(from, rpc);
+            self.send(&to, delivery);
+        }
+
+        for id in self.up() {
+            while let Some(committed) = self.raft(&id).consume() {
+                let applied = self.applied.get_mut(&id).unwrap();
+                match committed {
+                    Committed::Command(command) => applied.push(command),
+                    Committed::Snapshot(data) => *applied = serde_json::from_slice(&data).unwrap(),
+                    _ => {}
+                }
+            }
+
+            if self.rng.gen_bool(0.01) {
+                let data = serde_json::to_vec(&self.applied[&id]).unwrap();
+                self.raft(&id).snapshot(data);
+            }
+        }
+
+        self.check();
+    }
+
+    // proposes commands, and crashes, restarts and partitions nodes, at random
+    fn disrupt(&mut self) {
+        let up = self.up();
+
+        if self.rng.gen_bool(0.1) {
+            let node = up.choose(&mut self.rng).unwrap().clone();
+            self.next_command += 1;
+            let id = self.next_command;
+            let proposal = self.raft(&node).apply(id, format!("c{}", id));
+            self.send(&node, proposal.delivery());
+        }
+
+        // a majority stays up, or nothing would commit for long
+        if self.rng.gen_bool(0.005) && self.down.len() < (self.nodes.len() - 1) / 2 {
+            let node = up.choose(&mut self.rng).unwrap().clone();
+            self.down.insert(node);
+        }
+
+        if self.rng.gen_bool(0.02) {
+            if let Some(node) = self.down.iter().next().cloned() {
+                self.start(&node);
+            }
+        }
+
+        if self.rng.gen_bool(0.005) {
+            let ids = self.nodes.keys().cloned().collect::<Vec<_>>();
+            self.sides = ids
+                .into_iter()
+                .map(|id| (id, self.rng.gen_bool(0.5)))
+                .collect();
+        } else if self.rng.gen_bool(0.01) {
+            self.sides.clear();
+        }
+    }
+
+    fn check(&mut self) {
+        let seed = self.seed;
+        let up = self.up();
+
+        // election safety: at most one leader per term
+        for id in up.iter() {
+            let raft = &self.nodes[id];
+            if raft.is_leader() {
+                let leader = self.leaders.entry(raft.term()).or_insert(id.clone());
+                assert_eq!(
+                    leader,
+                    id,
+                    "seed {}: two leaders in term {}",
+                    seed,
+                    raft.term()
+                );
+            }
+        }
+
+        // log matching: logs that have an entry with the same index and term are the same up to
+        // it, as far back as both have entries
+        for (a, first) in self.nodes.iter() {
+            for (b, second) in self
+                .nodes
+                .range::<String, _>((Bound::Excluded(a), Bound::Unbounded))
+            {
+                let (first, second) = (&first.persistent, &second.persistent);
+                let start = first.offset().max(second.offset());
+                let end = first.len().min(second.len());
+
+                let matched = (start..end).rev().find(|index| {
+                    first.entry(*index).unwrap().term == second.entry(*index).unwrap().term
+                });
+
+                for index in start..matched.map_or(start, |index| index + 1) {
+                    assert_eq!(
+                        first.entry(index),
+                        second.entry(index),
+                        "seed {}: the logs of {} and {} differ at {}",
+                        seed,
+                        a,
+                        b,
+                        index
+                    );
+                }
+            }
+        }
+
+        // state machine safety: nodes apply the same commands in the same order
+        let term = self.nodes.values().map(|raft| raft.term()).max().unwrap();
+        for (id, applied) in self.applied.iter() {
+            let known = applied.len().min(self.committed.len());
+            let agreed = applied[..known]
+                .iter()
+                .zip(self.committed.iter())
+                .all(|(command, (committed, _))| command == committed);
+            assert!(agreed, "seed {}: {} applied {:?}", seed, id, applied);
+
+            for command in applied[known..].iter() {
+                self.committed.push((command.clone(), term));
+            }
+        }
+
+        // leader completeness: a leader elected after an entry committed has it
+        for id in up.iter() {
+            let raft = &self.nodes[id];
+            if !raft.is_leader() {
+                continue;
+            }
+
+            // a snapshot holds what was applied up to it, so with the commands in the log after
+            // it, this is every command in the leader's log
+            let persistent = &raft.persistent;
+            let mut held = persistent.snapshot.as_ref().map_or(Vec::new(), |snapshot| {
+                serde_json::from_slice::<Vec<String>>(&snapshot.data).unwrap()
+            });
+            held.extend(persistent.log.iter().filter_map(|log| match &log.entry {
+                Entry::Command(command) => Some(command.clone()),
+                _ => None,
+            }));
+
+            let before = self
+                .committed
+                .iter()
+                .take_while(|(_, term)| *term < raft.term())
+                .map(|(command, _)| command.clone())
+                .collect::<Vec<_>>();
+            assert!(
+                held.starts_with(&before),
+                "seed {}: leader {} of term {} is missing commands committed before it",
+                seed,
+                id,
+                raft.term()
+            );
+        }
+    }
+}
+
+#[test]
+fn chaos_preserves_safety() {
+    let seeds = match std::env::var("CHAOS_SEED") {
+        Ok(seed) => {
+            let seed = seed.parse().expect("CHAOS_SEED must be a number");
+            seed..seed + 1
+        }
+        Err(_) => 0..10,
+    };
+
+    for seed in seeds {
+        let mut chaos = Chaos::new(5, seed);
+        for _ in 0..3000 {
+            chaos.step();
+        }
+
+        // the network may be hostile, but not so much that nothing gets through
+        assert!(chaos.committed.len() > 10, "seed {}: too few commits", seed);
+    }
+}
+
+#[test]
+fn chaos_replays_from_its_seed() {
+    let run = || {
+        let mut chaos = Chaos::new(5, 3);
+        for _ in 0..1000 {
+            chaos.step();
+        }
+        (chaos.committed, chaos.leaders)
+    };
+
+    assert_eq!(run(), run());
+}
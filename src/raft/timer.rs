@@ -1,26 +1,69 @@
-use std::time::{Duration, Instant};
+use std::{ops::Range, rc::Rc, time::Duration};
 
-use rand::random;
+use rand::{rngs::StdRng, Rng, SeedableRng};
 
-#[derive(Clone, Debug)]
+use crate::Clock;
+
+#[derive(Clone)]
 pub struct Timer {
-    last: Instant,
+    clock: Rc<dyn Clock>,
+    last: Duration,
     timeout: Duration,
+    range: Range<Duration>,
+    rng: StdRng,
 }
 
 impl Timer {
-    pub fn new(base: u64, jitter: u64) -> Self {
+    pub fn new(clock: Rc<dyn Clock>, range: Range<Duration>) -> Self {
+        let mut rng = StdRng::from_entropy();
+
         Self {
-            timeout: Duration::from_millis(base + random::<u64>() % jitter),
-            last: Instant::now(),
+            timeout: Self::timeout(&range, &mut rng),
+            last: clock.now(),
+            clock,
+            range,
+            rng,
+        }
+    }
+
+    /// Keeps the timeouts drawn so far, but takes the time from `clock` from now on.
+    pub fn clock(mut self, clock: Rc<dyn Clock>) -> Self {
+        self.last = clock.now();
+        self.clock = clock;
+        self
+    }
+
+    /// Makes the timeouts reproducible.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.rng = StdRng::seed_from_u64(seed);
+        self.timeout = Self::timeout(&self.range, &mut self.rng);
+        self
+    }
+
+    fn timeout(range: &Range<Duration>, rng: &mut StdRng) -> Duration {
+        if range.is_empty() {
+            return range.start;
         }
+
+        rng.gen_range(range.clone())
     }
 
     pub fn expired(&self) -> bool {
-        self.last.elapsed() > self.timeout
+        self.clock.now() - self.last > self.timeout
+    }
+
+    /// The shortest the timeout can be.
+    pub fn min(&self) -> Duration {
+        self.range.start
+    }
+
+    pub fn now(&self) -> Duration {
+        self.clock.now()
     }
 
     pub fn reset(&mut self) {
-        self.last = Instant::now();
+        // a new timeout every time, so nodes that timed out together don't keep doing so
+        self.timeout = Self::timeout(&self.range, &mut self.rng);
+        self.last = self.clock.now();
     }
 }
@@ -0,0 +1,352 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tracing::warn;
+
+use super::{HardState, Log, Saved, Snapshot, Storage};
+
+// every record is its length and checksum, followed by that many bytes of json
+const HEADER: usize = 8;
+
+/// When `FileStorage` flushes writes to the disk.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fsync {
+    /// On every `sync`, so nothing Raft relied on is lost in a power failure.
+    Always,
+    /// Whenever the OS decides to. Survives the process crashing, but not the machine.
+    Never,
+}
+
+#[derive(Serialize, Deserialize)]
+enum Record<C> {
+    State(HardState),
+    Append { index: usize, entries: Vec<Log<C>> },
+    Snapshot(Snapshot),
+}
+
+/// A write-ahead log in a single file. A record cut short by a crash, or failing its
+/// checksum, is taken as the end of the log and cut off when opening. Taking a snapshot
+/// rewrites the file without the entries it covers.
+pub struct FileStorage<C> {
+    path: PathBuf,
+    file: File,
+    fsync: Fsync,
+    dirty: bool,
+    recovered: Option<Saved<C>>,
+}
+
+impl<C> FileStorage<C>
+where
+    C: Clone + Serialize + DeserializeOwned,
+{
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let mut file = Self::append_to(&path)?;
+
+        let (saved, len) = replay(&mut file)?;
+        let size = file.metadata()?.len();
+        if len < size {
+            warn!("Dropping {} bytes of torn writes", size - len);
+            file.set_len(len)?;
+            file.sync_data()?;
+        }
+
+        Ok(Self {
+            path,
+            file,
+            fsync: Fsync::Always,
+            dirty: false,
+            recovered: Some(saved),
+        })
+    }
+
+    pub fn fsync(mut self, fsync: Fsync) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    fn append_to(path: &Path) -> io::Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+    }
+
+    fn write(&mut self, record: &Record<C>) -> io::Result<()> {
+        self.file.write_all(&encode(record)?)?;
+        self.dirty = true;
+
+        Ok(())
+    }
+
+    // writes what the log holds now into a new file, which then replaces the log
+    fn rewrite(&mut self) -> io::Result<()> {
+        let (saved, _) = replay::<C>(&mut self.file)?;
+
+        let mut bytes = encode(&Record::<C>::State(saved.state.clone()))?;
+        if let Some(snapshot) = saved.snapshot.as_ref() {
+            bytes.extend(encode(&Record::<C>::Snapshot(snapshot.clone()))?);
+        }
+        bytes.extend(encode(&Record::Append {
+            index: saved.offset(),
+            entries: saved.log,
+        })?);
+
+        let temporary = self.path.with_extension("tmp");
+        let mut file = File::create(&temporary)?;
+        file.write_all(&bytes)?;
+        file.sync_data()?;
+
+        fs::rename(&temporary, &self.path)?;
+
+        // the rename itself is only durable once the directory holding the log is
+        if self.fsync == Fsync::Always {
+            let parent = self.path.parent().filter(|dir| !dir.as_os_str().is_empty());
+            File::open(parent.unwrap_or(Path::new(".")))?.sync_all()?;
+        }
+
+        self.file = Self::append_to(&self.path)?;
+
+        Ok(())
+    }
+}
+
+impl<C> Storage<C> for FileStorage<C>
+where
+    C: Clone + Serialize + DeserializeOwned,
+{
+    fn load(&mut self) -> io::Result<Saved<C>> {
+        Ok(self.recovered.take().unwrap_or_default())
+    }
+
+    fn save(&mut self, state: &HardState) -> io::Result<()> {
+        self.write(&Record::State(state.clone()))
+    }
+
+    fn append(&mut self, index: usize, entries: &[Log<C>]) -> io::Result<()> {
+        self.write(&Record::Append {
+            index,
+            entries: entries.to_vec(),
+        })
+    }
+
+    fn snapshot(&mut self, snapshot: &Snapshot) -> io::Result<()> {
+        self.write(&Record::Snapshot(snapshot.clone()))?;
+        self.sync()?;
+        self.rewrite()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        if self.dirty && self.fsync == Fsync::Always {
+            self.file.sync_data()?;
+        }
+
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+fn encode<C>(record: &Record<C>) -> io::Result<Vec<u8>>
+where
+    C: Serialize,
+{
+    let payload = serde_json::to_vec(record)?;
+
+    let mut bytes = Vec::with_capacity(HEADER + payload.len());
+    bytes.extend((payload.len() as u32).to_le_bytes());
+    bytes.extend(crc32(&payload).to_le_bytes());
+    bytes.extend(payload);
+
+    Ok(bytes)
+}
+
+// everything in `file` up to the first damaged record, and where that record starts
+fn replay<C>(file: &mut File) -> io::Result<(Saved<C>, u64)>
+where
+    C: DeserializeOwned,
+{
+    let mut bytes = Vec::new();
+    file.seek(SeekFrom::Start(0))?;
+    file.read_to_end(&mut bytes)?;
+
+    let mut saved = Saved::default();
+
+    let mut offset = 0;
+    while let Some((record, len)) = record::<C>(&bytes[offset..]) {
+        match record {
+            Record::State(state) => saved.state = state,
+            Record::Append { index, entries } => saved.append(index, entries),
+            Record::Snapshot(snapshot) => saved.compact(snapshot),
+        }
+
+        offset += len;
+    }
+
+    Ok((saved, offset as u64))
+}
+
+// the record at the start of `bytes` and its length, if it is whole and intact
+fn record<C>(bytes: &[u8]) -> Option<(Record<C>, usize)>
+where
+    C: DeserializeOwned,
+{
+    let header = bytes.get(..HEADER)?;
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(header[4..].try_into().unwrap());
+
+    let payload = bytes.get(HEADER..HEADER + len)?;
+    if crc32(payload) != checksum {
+        return None;
+    }
+
+    let record = serde_json::from_slice(payload).ok()?;
+    Some((record, HEADER + len))
+}
+
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                0xEDB88320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+
+        table[i] = crc;
+        i += 1;
+    }
+
+    table
+}
+
+fn crc32(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, byte| {
+        CRC32_TABLE[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::OpenOptions, io::Write, path::PathBuf};
+
+    use super::{crc32, FileStorage};
+    use crate::raft::{Entry, HardState, Log, Snapshot, Storage};
+
+    fn path(name: &str) -> PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("crabstorm-{}-{}.wal", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    fn log(terms: &[u32]) -> Vec<Log<String>> {
+        terms
+            .iter()
+            .map(|term| Log {
+                term: *term,
+                entry: Entry::Command(format!("command {}", term)),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn checksum() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn recover() {
+        let path = path("recover");
+        let state = HardState {
+            term: 3,
+            voted_for: Some("n1".to_string()),
+            commit_len: 1,
+        };
+
+        let mut storage = FileStorage::<String>::open(&path).unwrap();
+        storage.append(0, &log(&[1, 1, 2])).unwrap();
+        storage.save(&state).unwrap();
+        storage.append(2, &log(&[3])).unwrap();
+        storage.sync().unwrap();
+        drop(storage);
+
+        let mut storage = FileStorage::<String>::open(&path).unwrap();
+        let saved = storage.load().unwrap();
+        assert_eq!((saved.state, saved.log), (state, log(&[1, 1, 3])));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn torn_write() {
+        let path = path("torn");
+
+        let mut storage = FileStorage::<String>::open(&path).unwrap();
+        storage.append(0, &log(&[1, 2])).unwrap();
+        storage.append(2, &log(&[2])).unwrap();
+        storage.sync().unwrap();
+        drop(storage);
+
+        // cut the last record in half
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 5).unwrap();
+        drop(file);
+
+        let mut storage = FileStorage::<String>::open(&path).unwrap();
+        assert_eq!(storage.load().unwrap().log, log(&[1, 2]));
+
+        // and anything written after recovery is readable again
+        storage.append(2, &log(&[4])).unwrap();
+        drop(storage);
+
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"garbage").unwrap();
+        drop(file);
+
+        let mut storage = FileStorage::<String>::open(&path).unwrap();
+        assert_eq!(storage.load().unwrap().log, log(&[1, 2, 4]));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compaction() {
+        let path = path("compaction");
+        let snapshot = Snapshot {
+            len: 2,
+            term: 1,
+            config: None,
+            data: b"state".to_vec(),
+        };
+
+        let mut storage = FileStorage::<String>::open(&path).unwrap();
+        storage.append(0, &log(&[1, 1, 2])).unwrap();
+        storage.snapshot(&snapshot).unwrap();
+        storage.append(3, &log(&[2])).unwrap();
+        storage.sync().unwrap();
+        drop(storage);
+
+        let mut storage = FileStorage::<String>::open(&path).unwrap();
+        let saved = storage.load().unwrap();
+        assert_eq!(saved.snapshot, Some(snapshot));
+        assert_eq!(saved.log, log(&[2, 2]));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
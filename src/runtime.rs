@@ -2,38 +2,34 @@ use std::{
     fs::OpenOptions,
     io::Stdin,
     path::{Path, PathBuf},
+    rc::Rc,
     time::Duration,
 };
 
 use anyhow::{Error, Result};
-use futures::{
-    io::Lines,
-    select,
-    stream::{select_all, StreamExt},
-    AsyncBufReadExt, FutureExt,
-};
+use futures::{io::Lines, select, stream::StreamExt, AsyncBufReadExt, FutureExt};
 use inel::{io::RingBufReader, time::Interval, AsyncRingWriteExt};
 use serde::{Deserialize, Serialize};
-use tracing::{debug, level_filters::LevelFilter};
+use tracing::{debug, level_filters::LevelFilter, warn};
 
-use crate::{Body, Init, Message, Node, Sender};
+use crate::{
+    dispatcher::Dispatcher, node::RawMessage, Async, AsyncNode, Clock, Message, Node, SystemClock,
+    Wakeup,
+};
 
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(tag = "type")]
-#[serde(rename_all = "snake_case")]
-pub enum InitPayload {
-    Init(Init),
-    InitOk,
-}
+// how often pending requests, timers and intervals are checked for expiry
+const TICK: Duration = Duration::from_millis(10);
 
 pub struct InputHandler {
     input: Lines<RingBufReader<Stdin>>,
+    malformed: usize,
 }
 
 impl InputHandler {
     fn new() -> Self {
         Self {
             input: RingBufReader::new(std::io::stdin()).lines(),
+            malformed: 0,
         }
     }
 
@@ -41,55 +37,31 @@ impl InputHandler {
     where
         for<'a> P: Deserialize<'a>,
     {
-        self.input.next().await.map(|line| {
-            serde_json::from_str(&line.expect("Failed to read line"))
-                .expect("Failed to parse message")
-        })
-    }
-}
-
-struct OutputHandler<P> {
-    id: usize,
-    node: String,
-    sender: flume::Sender<(String, Option<usize>, P)>,
-    receiver: flume::Receiver<(String, Option<usize>, P)>,
-}
-
-impl<P> OutputHandler<P>
-where
-    P: Serialize,
-{
-    fn new(node: String) -> Self {
-        let (sender, receiver) = flume::unbounded();
-
-        Self {
-            id: 0,
-            node,
-            sender,
-            receiver,
+        while let Some(line) = self.input.next().await {
+            let message = line
+                .map_err(Error::from)
+                .and_then(|line| serde_json::from_str(&line).map_err(Error::from));
+
+            match message {
+                Ok(message) => return Some(message),
+                Err(err) => {
+                    self.malformed += 1;
+                    warn!(
+                        "Skipped malformed line ({} so far): {}",
+                        self.malformed, err
+                    );
+                }
+            }
         }
-    }
 
-    fn sender(&self) -> Sender<P> {
-        Sender::new(self.sender.clone())
+        None
     }
+}
 
-    async fn write<M>(&mut self, (dst, reply, payload): (String, Option<usize>, M))
-    where
-        M: Serialize,
-    {
-        let message = Message {
-            src: self.node.clone(),
-            dst,
-            body: Body {
-                id: Some(self.id),
-                reply,
-                payload,
-            },
-        };
-
-        self.id += 1;
+struct OutputHandler;
 
+impl OutputHandler {
+    async fn write(&mut self, message: RawMessage) {
         let mut bytes = serde_json::to_vec(&message).expect("Failed to serialize message");
         bytes.push(b'\n');
 
@@ -99,19 +71,11 @@ where
             .1
             .expect("Failed to write message");
     }
-
-    async fn flush(&mut self)
-    where
-        P: Serialize,
-    {
-        while let Ok(parts) = self.receiver.try_recv() {
-            self.write(parts).await;
-        }
-    }
 }
 
 pub struct Runtime<E> {
     intervals: Vec<(Duration, E)>,
+    clock: Option<Rc<dyn Clock>>,
     trace_file: Option<PathBuf>,
     trace_level: Option<LevelFilter>,
 }
@@ -120,16 +84,26 @@ impl<E> Runtime<E> {
     pub fn new() -> Self {
         Self {
             intervals: Vec::new(),
+            clock: None,
             trace_file: None,
             trace_level: None,
         }
     }
 
+    /// Delivers `event` through `Node::event` every `time`, from the moment the node is
+    /// initialized. Panics if `time` is zero, like `Timer::every`.
     pub fn event(mut self, time: Duration, event: E) -> Self {
+        assert!(!time.is_zero(), "a periodic event needs a nonzero period");
         self.intervals.push((time, event));
         self
     }
 
+    /// Replaces the system clock, e.g. with a `VirtualClock` advanced by hand.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Some(Rc::new(clock));
+        self
+    }
+
     pub fn trace_file(mut self, file: impl AsRef<Path>) -> Self {
         self.trace_file = Some(file.as_ref().to_owned());
         self
@@ -140,11 +114,11 @@ impl<E> Runtime<E> {
         self
     }
 
-    pub fn run<N, P>(self, mut node: N) -> Result<()>
+    pub fn run<N, P>(self, node: N) -> Result<()>
     where
         E: Clone + 'static,
         N: Node<Payload = P, Event = E> + 'static,
-        for<'a> P: Deserialize<'a> + Serialize,
+        for<'a> P: Deserialize<'a> + Serialize + 'static,
     {
         if let Some(file) = self.trace_file {
             let writer = OpenOptions::new()
@@ -167,44 +141,34 @@ impl<E> Runtime<E> {
 
         inel::block_on(async move {
             let mut input = InputHandler::new();
-            let mut events = select_all(
-                self.intervals
-                    .into_iter()
-                    .map(|i| Interval::new(i.0).map(move |_| i.1.clone())),
-            );
-
-            let init = input.next::<InitPayload>().await.unwrap();
+            let mut output = OutputHandler;
+            let mut ticks = Interval::new(TICK).fuse();
 
-            let InitPayload::Init(init_payload) = init.body.payload else {
-                return Err(Error::msg("bad init message"));
+            let Some(init) = input.next().await else {
+                return Err(Error::msg("missing init message"));
             };
 
-            let mut output = OutputHandler::new(init_payload.id.clone());
+            let clock = self.clock.unwrap_or_else(|| Rc::new(SystemClock::new()));
 
-            node.init(init_payload);
-
-            output
-                .write((init.src, init.body.id, InitPayload::InitOk))
-                .await;
+            let mut dispatcher = Dispatcher::new(node, self.intervals, clock);
+            dispatcher.init(init)?;
 
             loop {
-                output.flush().await;
+                for message in dispatcher.outbox() {
+                    output.write(message).await;
+                }
 
                 select! {
                     message = input.next().fuse() => {
-                        if let Some(message) = message {
-                            debug!("Message");
-                            node.message(message, output.sender());
-                        } else {
+                        let Some(message) = message else {
                             break;
-                        }
+                        };
+
+                        dispatcher.receive(message);
                     }
 
-                    event = events.next() => {
-                        if let Some(event) = event {
-                            debug!("Event");
-                            node.event(event, output.sender());
-                        }
+                    _ = ticks.next() => {
+                        dispatcher.tick();
                     },
                 };
             }
@@ -212,6 +176,27 @@ impl<E> Runtime<E> {
             Ok(())
         })
     }
+
+    pub fn run_async<N, P>(self, node: N) -> Result<()>
+    where
+        E: Clone + 'static,
+        N: AsyncNode<Payload = P, Event = E>,
+        for<'a> P: Deserialize<'a> + Serialize + 'static,
+    {
+        let intervals = self
+            .intervals
+            .into_iter()
+            .map(|(time, event)| (time, Wakeup::Event(event)))
+            .collect();
+
+        Runtime {
+            intervals,
+            clock: self.clock,
+            trace_file: self.trace_file,
+            trace_level: self.trace_level,
+        }
+        .run(Async::new(node))
+    }
 }
 
 impl<E> Default for Runtime<E> {
@@ -0,0 +1,269 @@
+use std::{future::Future, time::Duration};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::json;
+
+use crate::{
+    node::{Payload, RawMessage},
+    Context, Error, ErrorCode, RpcError, Sender,
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+enum Reply {
+    ReadOk { value: serde_json::Value },
+    WriteOk,
+    CasOk,
+    TsOk { ts: u64 },
+    Error(Error),
+}
+
+/// Client for one of Maelstrom's key-value services. Keys and values can be anything that
+/// serializes to JSON.
+#[derive(Clone, Debug)]
+pub struct Kv {
+    service: String,
+    timeout: Duration,
+}
+
+impl Kv {
+    pub fn seq() -> Self {
+        Self::new("seq-kv")
+    }
+
+    pub fn lin() -> Self {
+        Self::new("lin-kv")
+    }
+
+    pub fn lww() -> Self {
+        Self::new("lww-kv")
+    }
+
+    fn new(service: &str) -> Self {
+        Self {
+            service: service.to_string(),
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn read<P, E, K, T>(
+        &self,
+        ctx: &Context<P, E>,
+        key: K,
+    ) -> impl Future<Output = Result<T, Error>>
+    where
+        K: Serialize,
+        T: DeserializeOwned,
+    {
+        let reply = call(ctx, &self.service, self.timeout, read(key));
+        async move { read_ok(reply.await) }
+    }
+
+    pub fn write<P, E, K, V>(
+        &self,
+        ctx: &Context<P, E>,
+        key: K,
+        value: V,
+    ) -> impl Future<Output = Result<(), Error>>
+    where
+        K: Serialize,
+        V: Serialize,
+    {
+        let reply = call(ctx, &self.service, self.timeout, write(key, value));
+        async move { write_ok(reply.await) }
+    }
+
+    pub fn cas<P, E, K, V>(
+        &self,
+        ctx: &Context<P, E>,
+        key: K,
+        from: V,
+        to: V,
+        create: bool,
+    ) -> impl Future<Output = Result<(), Error>>
+    where
+        K: Serialize,
+        V: Serialize,
+    {
+        let reply = call(ctx, &self.service, self.timeout, cas(key, from, to, create));
+        async move { cas_ok(reply.await) }
+    }
+
+    pub fn read_with<P, E, K, T, F>(&self, sender: &Sender<P, E>, key: K, callback: F)
+    where
+        K: Serialize,
+        T: DeserializeOwned,
+        F: FnOnce(Result<T, Error>) -> E + 'static,
+    {
+        call_with(sender, &self.service, self.timeout, read(key), |reply| {
+            callback(read_ok(reply))
+        });
+    }
+
+    pub fn write_with<P, E, K, V, F>(&self, sender: &Sender<P, E>, key: K, value: V, callback: F)
+    where
+        K: Serialize,
+        V: Serialize,
+        F: FnOnce(Result<(), Error>) -> E + 'static,
+    {
+        call_with(
+            sender,
+            &self.service,
+            self.timeout,
+            write(key, value),
+            |reply| callback(write_ok(reply)),
+        );
+    }
+
+    pub fn cas_with<P, E, K, V, F>(
+        &self,
+        sender: &Sender<P, E>,
+        key: K,
+        from: V,
+        to: V,
+        create: bool,
+        callback: F,
+    ) where
+        K: Serialize,
+        V: Serialize,
+        F: FnOnce(Result<(), Error>) -> E + 'static,
+    {
+        let request = cas(key, from, to, create);
+        call_with(sender, &self.service, self.timeout, request, |reply| {
+            callback(cas_ok(reply))
+        });
+    }
+}
+
+/// Client for Maelstrom's `lin-tso` service, which hands out strictly increasing timestamps.
+#[derive(Clone, Debug)]
+pub struct Tso {
+    timeout: Duration,
+}
+
+impl Tso {
+    pub fn new() -> Self {
+        Self {
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn ts<P, E>(&self, ctx: &Context<P, E>) -> impl Future<Output = Result<u64, Error>> {
+        let reply = call(ctx, "lin-tso", self.timeout, json!({ "type": "ts" }));
+        async move { ts_ok(reply.await) }
+    }
+
+    pub fn ts_with<P, E, F>(&self, sender: &Sender<P, E>, callback: F)
+    where
+        F: FnOnce(Result<u64, Error>) -> E + 'static,
+    {
+        call_with(
+            sender,
+            "lin-tso",
+            self.timeout,
+            json!({ "type": "ts" }),
+            |reply| callback(ts_ok(reply)),
+        );
+    }
+}
+
+impl Default for Tso {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn read(key: impl Serialize) -> serde_json::Value {
+    json!({ "type": "read", "key": key })
+}
+
+fn write(key: impl Serialize, value: impl Serialize) -> serde_json::Value {
+    json!({ "type": "write", "key": key, "value": value })
+}
+
+fn cas<V: Serialize>(key: impl Serialize, from: V, to: V, create: bool) -> serde_json::Value {
+    json!({ "type": "cas", "key": key, "from": from, "to": to, "create_if_not_exists": create })
+}
+
+fn call<P, E>(
+    ctx: &Context<P, E>,
+    service: &str,
+    timeout: Duration,
+    request: serde_json::Value,
+) -> impl Future<Output = Result<Reply, Error>> {
+    let reply = ctx.request(service.to_string(), Payload::Raw(request), timeout);
+    async move { parse(reply.await) }
+}
+
+fn call_with<P, E, F>(
+    sender: &Sender<P, E>,
+    service: &str,
+    timeout: Duration,
+    request: serde_json::Value,
+    callback: F,
+) where
+    F: FnOnce(Result<Reply, Error>) -> E + 'static,
+{
+    sender.request(
+        service.to_string(),
+        Payload::Raw(request),
+        timeout,
+        |reply| callback(parse(reply)),
+    );
+}
+
+fn parse(reply: Result<RawMessage, RpcError>) -> Result<Reply, Error> {
+    let message = reply?;
+
+    match serde_json::from_value(message.body.payload) {
+        Ok(Reply::Error(error)) => Err(error),
+        Ok(reply) => Ok(reply),
+        Err(err) => Err(Error::new(ErrorCode::Crash, err.to_string())),
+    }
+}
+
+fn read_ok<T: DeserializeOwned>(reply: Result<Reply, Error>) -> Result<T, Error> {
+    match reply? {
+        Reply::ReadOk { value } => serde_json::from_value(value)
+            .map_err(|err| Error::new(ErrorCode::Crash, err.to_string())),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+fn write_ok(reply: Result<Reply, Error>) -> Result<(), Error> {
+    match reply? {
+        Reply::WriteOk => Ok(()),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+fn cas_ok(reply: Result<Reply, Error>) -> Result<(), Error> {
+    match reply? {
+        Reply::CasOk => Ok(()),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+fn ts_ok(reply: Result<Reply, Error>) -> Result<u64, Error> {
+    match reply? {
+        Reply::TsOk { ts } => Ok(ts),
+        reply => Err(unexpected(reply)),
+    }
+}
+
+fn unexpected(reply: Reply) -> Error {
+    Error::new(ErrorCode::Crash, format!("unexpected reply {:?}", reply))
+}
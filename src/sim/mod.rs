@@ -0,0 +1,746 @@
+//! Deterministic in-process network for testing nodes without Maelstrom.
+//!
+//! A `Sim` runs `count` copies of a `Node` named `n0`, `n1`, ... on virtual time. Every
+//! message between them is delivered after a random latency drawn from a seeded rng, and
+//! ties are broken in a fixed order, so a run is fully determined by its seed, including
+//! any faults injected along the way.
+
+mod nemesis;
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    rc::Rc,
+    time::Duration,
+};
+
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::json;
+use tracing::debug;
+
+use crate::{
+    dispatcher::Dispatcher,
+    node::{decode_reply, RawMessage},
+    Body, Clock, Message, Node, RpcError, VirtualClock,
+};
+
+pub use nemesis::*;
+
+const CLIENT: &str = "c0";
+
+// how long a reordered message is held back, at most
+const REORDER_DELAY: Duration = Duration::from_millis(100);
+
+struct Schedule {
+    nemesis: Nemesis,
+    interval: Duration,
+    next: Duration,
+    active: bool,
+}
+
+pub struct Sim<N>
+where
+    N: Node,
+{
+    count: usize,
+    factory: Box<dyn FnMut() -> N>,
+    intervals: Vec<(Duration, N::Event)>,
+
+    latency: Latency,
+    drop_rate: f64,
+    link_drop_rates: BTreeMap<(String, String), f64>,
+    duplicate_rate: f64,
+    reorder_rate: f64,
+    partition: BTreeSet<(String, String)>,
+    crashed: BTreeSet<String>,
+    schedule: Option<Schedule>,
+
+    rng: StdRng,
+    clock: VirtualClock,
+    nodes: BTreeMap<String, Dispatcher<N>>,
+
+    // in flight messages, ordered by arrival time and then by the order they were sent in
+    network: BTreeMap<(Duration, u64), RawMessage>,
+    sent: u64,
+
+    msg_id: usize,
+    replies: BTreeMap<usize, RawMessage>,
+}
+
+impl<N, P, E> Sim<N>
+where
+    N: Node<Payload = P, Event = E>,
+    P: Serialize + DeserializeOwned,
+    E: Clone,
+{
+    pub fn new(count: usize, seed: u64, factory: impl FnMut() -> N + 'static) -> Self {
+        Self {
+            count,
+            factory: Box::new(factory),
+            intervals: Vec::new(),
+            latency: Latency::Uniform(Duration::from_millis(1), Duration::from_millis(10)),
+            drop_rate: 0.0,
+            link_drop_rates: BTreeMap::new(),
+            duplicate_rate: 0.0,
+            reorder_rate: 0.0,
+            partition: BTreeSet::new(),
+            crashed: BTreeSet::new(),
+            schedule: None,
+            rng: StdRng::seed_from_u64(seed),
+            clock: VirtualClock::new(),
+            nodes: BTreeMap::new(),
+            network: BTreeMap::new(),
+            sent: 0,
+            msg_id: 0,
+            replies: BTreeMap::new(),
+        }
+    }
+
+    /// Same as `Runtime::event`, on virtual time.
+    pub fn event(mut self, time: Duration, event: E) -> Self {
+        assert!(!time.is_zero(), "a periodic event needs a nonzero period");
+        self.intervals.push((time, event));
+        self
+    }
+
+    /// Panics on a `Latency::Uniform` whose minimum is above its maximum.
+    pub fn latency(mut self, latency: Latency) -> Self {
+        if let Latency::Uniform(min, max) = latency {
+            assert!(min <= max, "uniform latency from {:?} to {:?}", min, max);
+        }
+
+        self.latency = latency;
+        self
+    }
+
+    /// Probability of losing a message between two nodes. Client messages are never lost.
+    /// Panics unless `rate` is within [0, 1], as do the other rates.
+    pub fn drop_rate(mut self, rate: f64) -> Self {
+        self.drop_rate = probability(rate);
+        self
+    }
+
+    pub fn duplicate_rate(mut self, rate: f64) -> Self {
+        self.duplicate_rate = probability(rate);
+        self
+    }
+
+    /// Probability of holding a message back long enough to arrive after later ones.
+    pub fn reorder_rate(mut self, rate: f64) -> Self {
+        self.reorder_rate = probability(rate);
+        self
+    }
+
+    pub fn nemesis(mut self, nemesis: Nemesis, interval: Duration) -> Self {
+        self.schedule = Some(Schedule {
+            nemesis,
+            interval,
+            next: self.clock.now() + interval,
+            active: false,
+        });
+        self
+    }
+
+    /// Overrides the drop rate of the link from `from` to `to`.
+    pub fn set_link_drop_rate(&mut self, from: &str, to: &str, rate: f64) {
+        let link = (from.to_string(), to.to_string());
+        self.link_drop_rates.insert(link, probability(rate));
+    }
+
+    /// Replaces the current partition, if any.
+    pub fn partition(&mut self, partition: Partition) {
+        debug!("Partition {:?}", partition);
+        self.partition = partition.links(&self.node_ids(), &mut self.rng);
+    }
+
+    /// Crashes `node`, losing its state and every message sent to it until it restarts.
+    pub fn crash(&mut self, node: &str) {
+        self.boot();
+
+        debug!("Crash {}", node);
+        if self.nodes.remove(node).is_some() {
+            self.crashed.insert(node.to_string());
+        }
+    }
+
+    /// Replaces a crashed node with a fresh one and initializes it again.
+    pub fn restart(&mut self, node: &str) {
+        if self.crashed.remove(node) {
+            debug!("Restart {}", node);
+            self.spawn(node);
+        }
+    }
+
+    /// Removes the partition and restarts every crashed node.
+    pub fn heal(&mut self) {
+        debug!("Heal");
+        self.partition.clear();
+
+        for node in std::mem::take(&mut self.crashed) {
+            self.spawn(&node);
+        }
+    }
+
+    pub fn stop_nemesis(&mut self) {
+        self.schedule = None;
+        self.heal();
+    }
+
+    pub fn now(&self) -> Duration {
+        self.clock.now()
+    }
+
+    /// The virtual clock shared by every node.
+    pub fn clock(&self) -> VirtualClock {
+        self.clock.clone()
+    }
+
+    pub fn node_ids(&self) -> Vec<String> {
+        (0..self.count).map(|i| format!("n{}", i)).collect()
+    }
+
+    pub fn node(&mut self, id: &str) -> &N {
+        self.boot();
+        self.nodes[id].node()
+    }
+
+    /// Sends a client request to `node`, returning its `msg_id`.
+    pub fn send(&mut self, node: &str, payload: P) -> usize {
+        self.boot();
+
+        let id = self.msg_id;
+        self.msg_id += 1;
+
+        let message = Message {
+            src: CLIENT.to_string(),
+            dst: node.to_string(),
+            body: Body {
+                id: Some(id),
+                reply: None,
+                payload: serde_json::to_value(payload).expect("Failed to serialize message"),
+            },
+        };
+
+        self.transmit(message);
+
+        id
+    }
+
+    /// Takes the reply to the client request `id`, if it has arrived.
+    pub fn reply(&mut self, id: usize) -> Option<Result<Message<P>, RpcError>> {
+        self.replies.remove(&id).map(decode_reply)
+    }
+
+    /// Sends a client request and runs the simulation until its reply arrives.
+    pub fn call(
+        &mut self,
+        node: &str,
+        payload: P,
+        timeout: Duration,
+    ) -> Result<Message<P>, RpcError> {
+        let id = self.send(node, payload);
+        let deadline = self.clock.now() + timeout;
+
+        loop {
+            if let Some(reply) = self.reply(id) {
+                return reply;
+            }
+
+            if self.next().is_none_or(|next| next > deadline) {
+                self.clock.set(deadline);
+                return Err(RpcError::Timeout);
+            }
+
+            self.step();
+        }
+    }
+
+    pub fn run_for(&mut self, duration: Duration) {
+        self.boot();
+
+        let end = self.clock.now() + duration;
+        while self.next().is_some_and(|next| next <= end) {
+            self.step();
+        }
+
+        self.clock.set(end);
+    }
+
+    /// Advances virtual time to the next message delivery or timer and processes it.
+    /// Returns `false` if there is nothing left to do.
+    pub fn step(&mut self) -> bool {
+        self.boot();
+
+        let Some(next) = self.next() else {
+            return false;
+        };
+
+        self.clock.set(next);
+
+        if self
+            .schedule
+            .as_ref()
+            .is_some_and(|schedule| schedule.next <= next)
+        {
+            self.strike();
+            return true;
+        }
+
+        let message = self
+            .network
+            .first_entry()
+            .filter(|entry| entry.key().0 <= next);
+        if let Some(entry) = message {
+            let message = entry.remove();
+            self.deliver(message);
+            return true;
+        }
+
+        for id in self.node_ids() {
+            let Some(node) = self.nodes.get_mut(&id) else {
+                continue;
+            };
+
+            if node.deadline().is_some_and(|deadline| deadline <= next) {
+                node.tick();
+                self.route(&id);
+            }
+        }
+
+        true
+    }
+
+    fn next(&self) -> Option<Duration> {
+        let message = self.network.keys().next().map(|(at, _)| *at);
+        let timer = self.nodes.values().filter_map(|node| node.deadline()).min();
+        let nemesis = self.schedule.as_ref().map(|schedule| schedule.next);
+
+        [message, timer, nemesis].into_iter().flatten().min()
+    }
+
+    fn strike(&mut self) {
+        let schedule = self.schedule.as_mut().unwrap();
+        schedule.next += schedule.interval;
+        schedule.active = !schedule.active;
+
+        if !schedule.active {
+            self.heal();
+            return;
+        }
+
+        let nemesis = schedule.nemesis.clone();
+        let node = self.node_ids().choose(&mut self.rng).unwrap().clone();
+
+        match nemesis {
+            Nemesis::Partition => {
+                let partition = match self.rng.gen_range(0..3) {
+                    0 => Partition::Halves,
+                    1 => Partition::MajoritiesRing,
+                    _ => Partition::Isolate(node),
+                };
+
+                self.partition(partition);
+            }
+
+            Nemesis::Crash => self.crash(&node),
+        }
+    }
+
+    fn boot(&mut self) {
+        if !self.nodes.is_empty() {
+            return;
+        }
+
+        for id in self.node_ids() {
+            self.spawn(&id);
+        }
+    }
+
+    fn spawn(&mut self, id: &str) {
+        let clock = Rc::new(self.clock.clone());
+        let mut node =
+            Dispatcher::new((self.factory)(), self.intervals.clone(), clock).seed(self.rng.gen());
+
+        let init = Message {
+            src: CLIENT.to_string(),
+            dst: id.to_string(),
+            body: Body {
+                id: None,
+                reply: None,
+                payload: json!({ "type": "init", "node_id": id, "node_ids": self.node_ids() }),
+            },
+        };
+
+        node.init(init).expect("Failed to init node");
+        node.outbox();
+
+        self.nodes.insert(id.to_string(), node);
+    }
+
+    fn deliver(&mut self, message: RawMessage) {
+        if message.dst == CLIENT {
+            if let Some(id) = message.body.reply {
+                self.replies.insert(id, message);
+            }
+
+            return;
+        }
+
+        let link = (message.src.clone(), message.dst.clone());
+        if self.partition.contains(&link) {
+            debug!("Dropping message across partition {:?}", link);
+            return;
+        }
+
+        let Some(node) = self.nodes.get_mut(&message.dst) else {
+            debug!("Dropping message to unavailable node {}", message.dst);
+            return;
+        };
+
+        let id = message.dst.clone();
+        node.receive(message);
+        self.route(&id);
+    }
+
+    fn route(&mut self, id: &str) {
+        for message in self.nodes.get_mut(id).unwrap().outbox() {
+            self.transmit(message);
+        }
+    }
+
+    fn transmit(&mut self, message: RawMessage) {
+        if message.src == CLIENT || message.dst == CLIENT {
+            self.enqueue(message);
+            return;
+        }
+
+        let link = (message.src.clone(), message.dst.clone());
+        let drop_rate = self.link_drop_rates.get(&link).unwrap_or(&self.drop_rate);
+        if self.rng.gen_bool(*drop_rate) {
+            debug!("Dropping message on {:?}", link);
+            return;
+        }
+
+        if self.rng.gen_bool(self.duplicate_rate) {
+            self.enqueue(message.clone());
+        }
+
+        self.enqueue(message);
+    }
+
+    fn enqueue(&mut self, message: RawMessage) {
+        let mut latency = self.latency.sample(&mut self.rng);
+        if self.rng.gen_bool(self.reorder_rate) {
+            latency += self.rng.gen_range(Duration::ZERO..=REORDER_DELAY);
+        }
+
+        self.network
+            .insert((self.clock.now() + latency, self.sent), message);
+        self.sent += 1;
+    }
+}
+
+// fails where a rate is set, rather than when the first message is sent
+fn probability(rate: f64) -> f64 {
+    assert!(
+        (0.0..=1.0).contains(&rate),
+        "rate {} is not a probability",
+        rate
+    );
+    rate
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{future, rc::Rc, task::Poll, time::Duration};
+
+    use rand::{rngs::StdRng, SeedableRng};
+    use serde::{Deserialize, Serialize};
+
+    use super::{Latency, Nemesis, Partition, Sim};
+    use crate::{
+        AsyncNode, Context, Error, ErrorCode, Init, Message, Node, RpcError, Sender, Timer, TimerId,
+    };
+
+    #[derive(Debug, Serialize, Deserialize)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "snake_case")]
+    enum RelayPayload {
+        Relay { to: String },
+        RelayOk { from: String },
+        Ping,
+        Pong,
+        Yield,
+        YieldOk,
+    }
+
+    struct RelayNode;
+
+    impl AsyncNode for RelayNode {
+        type Payload = RelayPayload;
+        type Event = ();
+
+        fn init(&mut self, _: Init, _: Context<RelayPayload, ()>) {}
+
+        async fn message(
+            self: Rc<Self>,
+            message: Message<RelayPayload>,
+            ctx: Context<RelayPayload, ()>,
+        ) -> Result<(), Error> {
+            match message.body.payload {
+                RelayPayload::Relay { to } => {
+                    let pong = ctx
+                        .call(to, RelayPayload::Ping, Duration::from_millis(100))
+                        .await?;
+
+                    ctx.sleep(Duration::from_millis(50)).await;
+
+                    let payload = RelayPayload::RelayOk { from: pong.src };
+                    ctx.send(message.src, message.body.id, payload);
+                }
+
+                RelayPayload::Ping => {
+                    ctx.send(message.src, message.body.id, RelayPayload::Pong);
+                }
+
+                // ready again straight away, with nothing else coming to poll it
+                RelayPayload::Yield => {
+                    let mut yielded = false;
+                    future::poll_fn(|cx| {
+                        if yielded {
+                            return Poll::Ready(());
+                        }
+
+                        yielded = true;
+                        cx.waker().wake_by_ref();
+                        Poll::Pending
+                    })
+                    .await;
+
+                    ctx.send(message.src, message.body.id, RelayPayload::YieldOk);
+                }
+
+                _ => return Err(ErrorCode::NotSupported.into()),
+            }
+
+            Ok(())
+        }
+    }
+
+    fn sim(seed: u64) -> Sim<crate::Async<RelayNode>> {
+        Sim::new(3, seed, || crate::Async::new(RelayNode))
+    }
+
+    #[test]
+    fn task_woken_while_polled_runs() {
+        let mut sim = sim(7);
+
+        let reply = sim.call("n0", RelayPayload::Yield, Duration::from_secs(1));
+        assert!(matches!(reply.unwrap().body.payload, RelayPayload::YieldOk));
+    }
+
+    #[test]
+    fn async_relay() {
+        let mut sim = sim(7);
+
+        let to = "n2".to_string();
+        let reply = sim.call("n0", RelayPayload::Relay { to }, Duration::from_secs(1));
+
+        let RelayPayload::RelayOk { from } = reply.unwrap().body.payload else {
+            panic!("unexpected reply");
+        };
+
+        assert_eq!(from, "n2");
+        assert!(sim.now() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn relay_timeout() {
+        let mut sim = sim(7);
+
+        let to = "n9".to_string();
+        let reply = sim.call("n1", RelayPayload::Relay { to }, Duration::from_secs(1));
+
+        let Err(RpcError::Remote(error)) = reply else {
+            panic!("unexpected reply {:?}", reply);
+        };
+
+        assert_eq!(error.code, ErrorCode::Timeout);
+    }
+
+    #[test]
+    fn not_supported() {
+        let mut sim = sim(7);
+
+        let reply = sim.call("n1", RelayPayload::Pong, Duration::from_secs(1));
+
+        let Err(RpcError::Remote(error)) = reply else {
+            panic!("unexpected reply {:?}", reply);
+        };
+
+        assert_eq!(error.code, ErrorCode::NotSupported);
+    }
+
+    #[test]
+    fn partition_and_heal() {
+        let mut sim = sim(7);
+        sim.partition(Partition::Isolate("n2".to_string()));
+
+        let to = "n2".to_string();
+        let reply = sim.call("n0", RelayPayload::Relay { to }, Duration::from_secs(1));
+        assert!(matches!(reply, Err(RpcError::Remote(error)) if error.code == ErrorCode::Timeout));
+
+        sim.heal();
+
+        let to = "n2".to_string();
+        let reply = sim.call("n0", RelayPayload::Relay { to }, Duration::from_secs(1));
+        assert!(matches!(
+            reply.unwrap().body.payload,
+            RelayPayload::RelayOk { .. }
+        ));
+    }
+
+    #[test]
+    fn majorities_ring_is_symmetric() {
+        let nodes = (0..6).map(|i| format!("n{}", i)).collect::<Vec<_>>();
+        let mut rng = StdRng::seed_from_u64(7);
+
+        for _ in 0..20 {
+            let links = Partition::MajoritiesRing.links(&nodes, &mut rng);
+
+            for (from, to) in links.iter() {
+                assert!(links.contains(&(to.clone(), from.clone())));
+            }
+
+            // everyone still sees a majority, themselves included
+            for node in nodes.iter() {
+                let cut = links.iter().filter(|(from, _)| from == node).count();
+                assert!(nodes.len() - cut >= 4);
+            }
+        }
+    }
+
+    #[test]
+    fn crash_and_restart() {
+        let mut sim = sim(7);
+        sim.crash("n1");
+
+        let reply = sim.call("n1", RelayPayload::Ping, Duration::from_secs(1));
+        assert!(matches!(reply, Err(RpcError::Timeout)));
+
+        sim.restart("n1");
+
+        let reply = sim.call("n1", RelayPayload::Ping, Duration::from_secs(1));
+        assert!(matches!(reply.unwrap().body.payload, RelayPayload::Pong));
+    }
+
+    #[test]
+    fn faults_are_deterministic() {
+        let run = |seed| {
+            let mut sim = sim(seed)
+                .drop_rate(0.2)
+                .duplicate_rate(0.2)
+                .reorder_rate(0.2)
+                .nemesis(Nemesis::Partition, Duration::from_millis(300));
+
+            (0..20)
+                .map(|i| {
+                    let to = format!("n{}", i % 3);
+                    let node = format!("n{}", (i + 1) % 3);
+                    let reply = sim.call(&node, RelayPayload::Relay { to }, Duration::from_secs(1));
+                    (reply.is_ok(), sim.now())
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(run(3), run(3));
+    }
+
+    #[derive(Debug, Serialize, Deserialize)]
+    #[serde(tag = "type")]
+    #[serde(rename_all = "snake_case")]
+    enum TickPayload {
+        Start,
+        Stop,
+        Ok { ticks: usize },
+    }
+
+    #[derive(Default)]
+    struct TickNode {
+        timer: Option<TimerId>,
+        ticks: usize,
+    }
+
+    impl Node for TickNode {
+        type Payload = TickPayload;
+        type Event = ();
+
+        fn init(&mut self, _: Init, _: Sender<TickPayload>) {}
+
+        fn message(
+            &mut self,
+            message: Message<TickPayload>,
+            sender: Sender<TickPayload>,
+        ) -> Result<(), Error> {
+            match message.body.payload {
+                TickPayload::Start => {
+                    let timer = Timer::every(Duration::from_millis(10), ())
+                        .jitter(Duration::from_millis(5));
+                    self.timer = Some(sender.schedule(timer));
+                }
+
+                TickPayload::Stop => sender.cancel(self.timer.take().unwrap()),
+
+                _ => return Err(ErrorCode::NotSupported.into()),
+            }
+
+            let ticks = self.ticks;
+            sender.send(message.src, message.body.id, TickPayload::Ok { ticks });
+
+            Ok(())
+        }
+
+        fn event(&mut self, _: (), _: Sender<TickPayload>) {
+            self.ticks += 1;
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "not a probability")]
+    fn rate_above_one_is_rejected() {
+        let _ = sim(1).drop_rate(1.5);
+    }
+
+    #[test]
+    #[should_panic(expected = "uniform latency")]
+    fn inverted_latency_range_is_rejected() {
+        let latency = Latency::Uniform(Duration::from_millis(10), Duration::from_millis(1));
+        let _ = sim(1).latency(latency);
+    }
+
+    #[test]
+    #[should_panic(expected = "nonzero period")]
+    fn zero_period_is_rejected() {
+        let _ = Sim::new(1, 7, TickNode::default).event(Duration::ZERO, ());
+    }
+
+    #[test]
+    fn schedule_and_cancel() {
+        let mut sim = Sim::new(1, 7, TickNode::default);
+
+        sim.call("n0", TickPayload::Start, Duration::from_secs(1))
+            .unwrap();
+        sim.run_for(Duration::from_secs(1));
+
+        let reply = sim.call("n0", TickPayload::Stop, Duration::from_secs(1));
+        let TickPayload::Ok { ticks } = reply.unwrap().body.payload else {
+            panic!("unexpected reply");
+        };
+
+        // one tick every 10 to 15ms
+        assert!((66..=100).contains(&ticks), "{} ticks", ticks);
+
+        sim.run_for(Duration::from_secs(1));
+        assert_eq!(sim.node("n0").ticks, ticks);
+    }
+}
@@ -0,0 +1,111 @@
+use std::{collections::BTreeSet, time::Duration};
+
+use rand::{seq::SliceRandom, Rng};
+
+#[derive(Clone, Debug)]
+pub enum Latency {
+    Constant(Duration),
+    Uniform(Duration, Duration),
+    Exponential(Duration),
+}
+
+impl Latency {
+    pub(crate) fn sample(&self, rng: &mut impl Rng) -> Duration {
+        match self {
+            Latency::Constant(latency) => *latency,
+            Latency::Uniform(min, max) => rng.gen_range(*min..=*max),
+            Latency::Exponential(mean) => mean.mul_f64(-(1.0 - rng.gen::<f64>()).ln()),
+        }
+    }
+}
+
+/// Ways of cutting the network, mirroring Maelstrom's partition nemesis.
+#[derive(Clone, Debug)]
+pub enum Partition {
+    /// Two random halves that can't talk to each other.
+    Halves,
+    /// One node cut off from everyone else.
+    Isolate(String),
+    /// Nodes placed on a random ring, each one only seeing the majority around it, so no
+    /// two nodes see the same majority. Links stay symmetric: with an even number of nodes,
+    /// a node also sees those whose majority it's in, which can be one more.
+    MajoritiesRing,
+    /// Explicit groups, nodes in different groups can't talk to each other.
+    Groups(Vec<Vec<String>>),
+}
+
+impl Partition {
+    /// The directed links `(from, to)` this partition cuts.
+    pub(crate) fn links(&self, nodes: &[String], rng: &mut impl Rng) -> BTreeSet<(String, String)> {
+        let groups = match self {
+            Partition::Halves => {
+                let mut nodes = nodes.to_vec();
+                nodes.shuffle(rng);
+
+                let other = nodes.split_off(nodes.len() / 2);
+                vec![nodes, other]
+            }
+
+            Partition::Isolate(node) => {
+                let others = nodes.iter().filter(|n| *n != node).cloned().collect();
+                vec![vec![node.clone()], others]
+            }
+
+            Partition::MajoritiesRing => return majorities_ring(nodes, rng),
+
+            Partition::Groups(groups) => groups.clone(),
+        };
+
+        let group = |node: &String| groups.iter().position(|group| group.contains(node));
+
+        pairs(nodes)
+            .filter(|(from, to)| group(from) != group(to))
+            .collect()
+    }
+}
+
+fn majorities_ring(nodes: &[String], rng: &mut impl Rng) -> BTreeSet<(String, String)> {
+    let mut ring = nodes.to_vec();
+    ring.shuffle(rng);
+
+    let count = ring.len();
+    let majority = count / 2 + 1;
+
+    // the majority seen by each node is the window of the ring starting just before it
+    let windows = (0..count)
+        .map(|i| {
+            (0..majority)
+                .map(|offset| &ring[(i + count + offset - (majority - 1) / 2) % count])
+                .collect::<BTreeSet<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    // a link is only cut if neither side has the other in its window
+    let mut links = BTreeSet::new();
+    for (i, from) in ring.iter().enumerate() {
+        for (j, to) in ring.iter().enumerate() {
+            if !windows[i].contains(to) && !windows[j].contains(from) {
+                links.insert((from.clone(), to.clone()));
+            }
+        }
+    }
+
+    links
+}
+
+fn pairs(nodes: &[String]) -> impl Iterator<Item = (String, String)> + '_ {
+    nodes
+        .iter()
+        .flat_map(move |from| nodes.iter().map(move |to| (from.clone(), to.clone())))
+        .filter(|(from, to)| from != to)
+}
+
+/// Faults the simulator injects on its own, alternating every `interval` between
+/// breaking something and healing it, like Maelstrom's `--nemesis` option.
+#[derive(Clone, Debug)]
+pub enum Nemesis {
+    /// A random `Halves`, `MajoritiesRing` or `Isolate` partition.
+    Partition,
+    /// Crashes a random node, restarting it when healing.
+    Crash,
+}
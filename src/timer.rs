@@ -0,0 +1,54 @@
+use std::time::Duration;
+
+/// Identifies a timer scheduled with `Sender::schedule`, so it can be cancelled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct TimerId(pub(crate) usize);
+
+/// An event delivered through `Node::event` after a delay, once or periodically.
+#[derive(Clone, Debug)]
+pub struct Timer<E> {
+    pub(crate) delay: Duration,
+    pub(crate) period: Option<Duration>,
+    pub(crate) jitter: Duration,
+    pub(crate) event: E,
+}
+
+impl<E> Timer<E> {
+    pub fn once(delay: Duration, event: E) -> Self {
+        Self {
+            delay,
+            period: None,
+            jitter: Duration::ZERO,
+            event,
+        }
+    }
+
+    /// Fires every `period`, starting one `period` from now, until cancelled. Panics if
+    /// `period` is zero, as the timer would then keep firing without time moving on.
+    pub fn every(period: Duration, event: E) -> Self {
+        assert!(!period.is_zero(), "a periodic timer needs a nonzero period");
+
+        Self {
+            delay: period,
+            period: Some(period),
+            jitter: Duration::ZERO,
+            event,
+        }
+    }
+
+    /// Delays every firing by a random amount up to `jitter`, so that nodes retrying the
+    /// same thing don't do it in lockstep.
+    pub fn jitter(mut self, jitter: Duration) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    pub(crate) fn map<F>(self, f: impl FnOnce(E) -> F) -> Timer<F> {
+        Timer {
+            delay: self.delay,
+            period: self.period,
+            jitter: self.jitter,
+            event: f(self.event),
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use core::hash::Hash;
-use std::hash::Hasher;
+use std::{cmp::Ordering, hash::Hasher};
 
 use serde::{Deserialize, Serialize};
 
@@ -37,3 +37,56 @@ fn hash_value<H: Hasher>(value: &serde_json::Value, state: &mut H) {
         }
     }
 }
+
+// orders values of different kinds as null < bools < numbers < strings < arrays < objects, so
+// that keys can be split into ranges
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        cmp_value(&self.0, &other.0)
+    }
+}
+
+impl PartialOrd for Value {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn rank(value: &serde_json::Value) -> u8 {
+    match value {
+        serde_json::Value::Null => 0,
+        serde_json::Value::Bool(_) => 1,
+        serde_json::Value::Number(_) => 2,
+        serde_json::Value::String(_) => 3,
+        serde_json::Value::Array(_) => 4,
+        serde_json::Value::Object(_) => 5,
+    }
+}
+
+fn cmp_value(a: &serde_json::Value, b: &serde_json::Value) -> Ordering {
+    match (a, b) {
+        (serde_json::Value::Bool(a), serde_json::Value::Bool(b)) => a.cmp(b),
+        // 1 and 1.0 aren't equal, so numbers that are the same as floats still need an order
+        (serde_json::Value::Number(a), serde_json::Value::Number(b)) => {
+            let (x, y) = (a.as_f64().unwrap_or(0.0), b.as_f64().unwrap_or(0.0));
+            x.total_cmp(&y)
+                .then_with(|| a.as_i64().cmp(&b.as_i64()))
+                .then_with(|| a.as_u64().cmp(&b.as_u64()))
+                .then_with(|| a.to_string().cmp(&b.to_string()))
+        }
+        (serde_json::Value::String(a), serde_json::Value::String(b)) => a.cmp(b),
+        (serde_json::Value::Array(a), serde_json::Value::Array(b)) => a
+            .iter()
+            .zip(b)
+            .map(|(a, b)| cmp_value(a, b))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| a.len().cmp(&b.len())),
+        (serde_json::Value::Object(a), serde_json::Value::Object(b)) => a
+            .iter()
+            .zip(b)
+            .map(|((k, a), (l, b))| k.cmp(l).then_with(|| cmp_value(a, b)))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| a.len().cmp(&b.len())),
+        _ => rank(a).cmp(&rank(b)),
+    }
+}